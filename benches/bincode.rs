@@ -0,0 +1,34 @@
+// cargo bench --features bincode --bench bincode
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use cryptoconditions::*;
+use libsecp256k1::{PublicKey, SecretKey};
+
+// signed 1 of 3 threshold with anon subconditions, like the last of the serialize tests variants
+fn threshold() -> Condition {
+    let secret = SecretKey::parse(&[1; 32]).unwrap();
+    let mut cond = Threshold {
+        threshold: 1,
+        subconditions: vec![
+            Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None },
+            Threshold { threshold: 1, subconditions: vec![Eval { code: vec![2] }] }.to_anon(),
+            Preimage { preimage: vec![9] }.to_anon(),
+        ],
+    };
+    assert_eq!(cond.sign_multi(&[secret], b"msg"), 1);
+    cond
+}
+
+fn bench_round_trip(c: &mut Criterion) {
+    let cond = threshold();
+    assert_eq!(from_bincode(&to_bincode(&cond)), Ok(cond.clone()));
+
+    let mut group = c.benchmark_group("threshold round trip");
+    group.bench_function("der", |b| {
+        b.iter(|| decode_fulfillment(&black_box(&cond).encode_fulfillment(0).unwrap(), 0).unwrap())
+    });
+    group.bench_function("bincode", |b| b.iter(|| from_bincode(&to_bincode(black_box(&cond))).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_round_trip);
+criterion_main!(benches);
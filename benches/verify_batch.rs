@@ -0,0 +1,40 @@
+// cargo bench [--features rayon] --bench verify_batch
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use cryptoconditions::*;
+use libsecp256k1::{PublicKey, SecretKey};
+
+// 2 of 3 thresholds signed by two keys, each over its own message
+fn small_thresholds(count: u8) -> Vec<(Condition, Vec<u8>)> {
+    let keys = [SecretKey::parse(&[1; 32]).unwrap(), SecretKey::parse(&[2; 32]).unwrap()];
+    (0..count)
+        .map(|i| {
+            let msg = vec![i; 32];
+            let mut cond = Threshold {
+                threshold: 2,
+                subconditions: vec![
+                    Secp256k1 { pubkey: PublicKey::from_secret_key(&keys[0]), signature: None },
+                    Secp256k1 { pubkey: PublicKey::from_secret_key(&keys[1]), signature: None },
+                    Eval { code: vec![i] },
+                ],
+            };
+            assert_eq!(cond.sign_multi(&keys, &msg), 2);
+            (cond, msg)
+        })
+        .collect()
+}
+
+fn bench_verify_batch(c: &mut Criterion) {
+    let items = small_thresholds(200);
+    let naive: Vec<VerifyOutcome> = items.iter().map(|(cond, msg)| verify(cond, msg)).collect();
+    assert_eq!(naive, verify_batch(&items));
+
+    let mut group = c.benchmark_group("verify 200 small thresholds");
+    group.bench_function("naive loop", |b| {
+        b.iter(|| items.iter().map(|(cond, msg)| verify(cond, msg)).collect::<Vec<VerifyOutcome>>())
+    });
+    group.bench_function("verify_batch", |b| b.iter(|| verify_batch(black_box(&items))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_verify_batch);
+criterion_main!(benches);
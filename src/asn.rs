@@ -0,0 +1,199 @@
+#[cfg(any(not(feature = "der-reader"), test))]
+use num_bigint::BigUint;
+#[cfg(any(not(feature = "der-reader"), test))]
+use num_traits::cast::ToPrimitive;
+#[cfg(any(not(feature = "der-reader"), test))]
+use simple_asn1::{from_der, to_der, ASN1Block, ASN1Class};
+
+use crate::ConditionDecodeError;
+
+// ASN.1 library conditions are encoded and decoded with. simple_asn1 is the default, the der-reader feature
+// switches to the der crate, which only reads strict DER (minimal lengths, constructed SEQUENCEs), without
+// changing the encoding and decoding code
+
+type R<T> = Result<T, ConditionDecodeError>;
+
+fn err(s: &str) -> ConditionDecodeError {
+    ConditionDecodeError::Malformed(s.into())
+}
+
+pub(crate) trait AsnBackend {
+    type Element: Clone;
+    /// the top level elements of data
+    fn from_der(data: &[u8]) -> R<Vec<Self::Element>>;
+    /// (tag, content) of a context specific element, content left undecoded
+    fn context_specific(elem: Self::Element) -> R<(u8, Vec<u8>)>;
+    /// tag of a context specific element
+    fn context_tag(elem: &Self::Element) -> Option<u8>;
+    /// children of a SEQUENCE
+    fn sequence(elem: &Self::Element) -> Option<Vec<Self::Element>>;
+    fn is_null(elem: &Self::Element) -> bool;
+    fn to_der(elem: &Self::Element) -> R<Vec<u8>>;
+    /// context specific element with the encoded content
+    fn new_context_specific(constructed: bool, tag: u8, content: Vec<u8>) -> R<Self::Element>;
+    fn new_sequence(children: &[Self::Element]) -> R<Self::Element>;
+}
+
+#[cfg(not(feature = "der-reader"))]
+pub(crate) type Backend = SimpleAsn1;
+#[cfg(feature = "der-reader")]
+pub(crate) type Backend = DerAsn;
+
+// elements of the selected backend
+pub(crate) type Element = <Backend as AsnBackend>::Element;
+
+#[cfg(any(not(feature = "der-reader"), test))]
+pub(crate) struct SimpleAsn1;
+
+#[cfg(any(not(feature = "der-reader"), test))]
+impl AsnBackend for SimpleAsn1 {
+    type Element = ASN1Block;
+
+    fn from_der(data: &[u8]) -> R<Vec<ASN1Block>> {
+        from_der(data).map_err(|_| err("Invalid ASN data1"))
+    }
+    fn context_specific(elem: ASN1Block) -> R<(u8, Vec<u8>)> {
+        match elem {
+            ASN1Block::Unknown(ASN1Class::ContextSpecific, _, _, type_id, buf) => {
+                Ok((type_id.to_u8().ok_or(err("Invalid type id"))?, buf))
+            },
+            _ => Err(err("unexpected structure2")),
+        }
+    }
+    fn context_tag(elem: &ASN1Block) -> Option<u8> {
+        match elem {
+            ASN1Block::Unknown(ASN1Class::ContextSpecific, _, _, type_id, _) => type_id.to_u8(),
+            _ => None,
+        }
+    }
+    fn sequence(elem: &ASN1Block) -> Option<Vec<ASN1Block>> {
+        match elem {
+            ASN1Block::Sequence(_, children) => Some(children.clone()),
+            _ => None,
+        }
+    }
+    fn is_null(elem: &ASN1Block) -> bool {
+        matches!(elem, ASN1Block::Null(_))
+    }
+    fn to_der(elem: &ASN1Block) -> R<Vec<u8>> {
+        to_der(elem).map_err(|_| err("Can't encode ASN"))
+    }
+    fn new_context_specific(constructed: bool, tag: u8, content: Vec<u8>) -> R<ASN1Block> {
+        Ok(ASN1Block::Unknown(ASN1Class::ContextSpecific, constructed, 0, BigUint::from(tag), content))
+    }
+    fn new_sequence(children: &[ASN1Block]) -> R<ASN1Block> {
+        Ok(ASN1Block::Sequence(0, children.to_vec()))
+    }
+}
+
+/// backend of the der crate, contents of all elements are left undecoded
+#[cfg(feature = "der-reader")]
+pub(crate) struct DerAsn;
+
+#[cfg(feature = "der-reader")]
+impl AsnBackend for DerAsn {
+    type Element = der::asn1::Any;
+
+    fn from_der(data: &[u8]) -> R<Vec<der::asn1::Any>> {
+        use der::{Decode, Reader};
+        let mut reader = der::SliceReader::new(data).map_err(|_| err("Invalid ASN data1"))?;
+        let mut out = Vec::new();
+        while !reader.is_finished() {
+            out.push(der::asn1::Any::decode(&mut reader).map_err(|_| err("Invalid ASN data1"))?);
+        }
+        Ok(out)
+    }
+    fn context_specific(elem: der::asn1::Any) -> R<(u8, Vec<u8>)> {
+        match Self::context_tag(&elem) {
+            Some(tag) => Ok((tag, elem.value().to_vec())),
+            _ => Err(err("unexpected structure2")),
+        }
+    }
+    fn context_tag(elem: &der::asn1::Any) -> Option<u8> {
+        use der::Tagged;
+        match elem.tag() {
+            der::Tag::ContextSpecific { number, .. } => Some(number.value()),
+            _ => None,
+        }
+    }
+    fn sequence(elem: &der::asn1::Any) -> Option<Vec<der::asn1::Any>> {
+        use der::Tagged;
+        match elem.tag() {
+            der::Tag::Sequence => Self::from_der(elem.value()).ok(),
+            _ => None,
+        }
+    }
+    fn is_null(elem: &der::asn1::Any) -> bool {
+        use der::Tagged;
+        elem.tag() == der::Tag::Null && elem.value().is_empty()
+    }
+    fn to_der(elem: &der::asn1::Any) -> R<Vec<u8>> {
+        use der::Encode;
+        elem.to_der().map_err(|_| err("Can't encode ASN"))
+    }
+    fn new_context_specific(constructed: bool, tag: u8, content: Vec<u8>) -> R<der::asn1::Any> {
+        use std::convert::TryFrom;
+        let number = der::TagNumber::try_from(tag).map_err(|_| err("Invalid type id"))?;
+        der::asn1::Any::new(der::Tag::ContextSpecific { constructed, number }, content).map_err(|_| err("Can't encode ASN"))
+    }
+    fn new_sequence(children: &[der::asn1::Any]) -> R<der::asn1::Any> {
+        let content = children.iter().map(Self::to_der).collect::<R<Vec<_>>>()?.concat();
+        der::asn1::Any::new(der::Tag::Sequence, content).map_err(|_| err("Can't encode ASN"))
+    }
+}
+
+#[cfg(all(test, feature = "der-reader"))]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    // elements of both backends as (context tag, is null, der)
+    fn elements<B: AsnBackend>(data: &[u8]) -> Vec<(Option<u8>, bool, Vec<u8>)> {
+        B::from_der(data).unwrap().iter().map(|e| (B::context_tag(e), B::is_null(e), B::to_der(e).unwrap())).collect()
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                Preimage { preimage: vec![1; 200] },
+                Prefix { prefix: vec![7], max_message_len: 32, subcondition: Box::new(Eval { code: vec![1] }) },
+            ]
+        };
+        let ffil = cond.encode_fulfillment(0).unwrap();
+        for data in vec![ffil.clone(), cond.encode_condition(), hex::decode("3003a0010005000500").unwrap()] {
+            assert_eq!(elements::<SimpleAsn1>(&data), elements::<DerAsn>(&data));
+        }
+
+        let (tag, content) = DerAsn::context_specific(DerAsn::from_der(&ffil).unwrap().remove(0)).unwrap();
+        assert_eq!((tag, content), SimpleAsn1::context_specific(SimpleAsn1::from_der(&ffil).unwrap().remove(0)).unwrap());
+        let wrapped = [&[0x30, 0x03][..], &hex::decode("a00100").unwrap()[..]].concat();
+        assert_eq!(DerAsn::sequence(&DerAsn::from_der(&wrapped).unwrap()[0]).map(|s| s.len()), Some(1));
+
+        // both encode the same
+        let encode = |constructed| {
+            let simple = SimpleAsn1::new_context_specific(constructed, 2, vec![1; 200]).unwrap();
+            let der = DerAsn::new_context_specific(constructed, 2, vec![1; 200]).unwrap();
+            let simple = SimpleAsn1::to_der(&SimpleAsn1::new_sequence(&[simple]).unwrap()).unwrap();
+            assert_eq!(DerAsn::to_der(&DerAsn::new_sequence(&[der]).unwrap()).unwrap(), simple);
+        };
+        encode(true);
+        encode(false);
+
+        // truncated
+        assert!(DerAsn::from_der(&ffil[..ffil.len() - 1]).is_err());
+        assert!(DerAsn::from_der(&[0xa0, 0x80, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_der_strict() {
+        // preimage fulfillment of [1] with its length in the long form, which is not minimal
+        let long_length = hex::decode("a08103800101").unwrap();
+        assert!(DerAsn::from_der(&long_length).is_err());
+        assert!(DerAsn::from_der(&hex::decode("a003800101").unwrap()).is_ok());
+        // SEQUENCE without the constructed bit
+        assert!(DerAsn::from_der(&hex::decode("1003a00100").unwrap()).is_err());
+        assert!(decode_fulfillment(&long_length, 0).is_err());
+    }
+}
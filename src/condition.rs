@@ -1,10 +1,19 @@
-use num_bigint::{BigInt, BigUint};
+use num_bigint::BigInt;
 use num_traits::cast::FromPrimitive;
 use libsecp256k1::{PublicKey, Signature, SecretKey, Message, sign};
-use simple_asn1::{to_der, ASN1Block, ASN1Class};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+use crate::asn::Element;
 
 pub const MIXED_MODE: u32 = 0x01;
+// decode secp256k1 fulfillments without the signature element (unsigned templates) as signature: None
+pub const ALLOW_NO_SIGNATURE: u32 = 0x02;
+// decode conditions and fulfillments wrapped in an outer SEQUENCE by some tools, unwrapped ones are read too
+pub const SEQUENCE_WRAPPED: u32 = 0x04;
+// decode secp256k1 signatures with r and s each in little endian byte order, as written by a legacy encoder
+pub const SWAPPED_SIGNATURE_BYTES: u32 = 0x08;
+// decode an ASN.1 NULL in the signature position of a secp256k1 fulfillment as signature: None
+pub const NULL_SIGNATURE: u32 = 0x10;
 
 pub use Condition::*;
 pub use crate::pad_fingerprint;
@@ -13,7 +22,7 @@ pub use crate::shrink_fingerprint;
 use log::Level;
 use log::info;
 
-#[derive(Clone, PartialEq, Debug, Copy)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Copy)]
 pub enum ConditionType {
     AnonType,
     PreimageType,
@@ -55,6 +64,30 @@ impl ConditionType {
     }
 }
 
+/// hash function of fingerprints, sha256 (Sha256Hasher) in the spec
+pub trait Hasher: Default {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> Vec<u8>;
+}
+
+#[derive(Default)]
+pub struct Sha256Hasher(sha2::Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::input(&mut self.0, data);
+    }
+    fn finalize(self) -> Vec<u8> {
+        sha2::Digest::result(self.0).to_vec()
+    }
+}
+
+fn hash_with<H: Hasher>(data: &[u8]) -> Vec<u8> {
+    let mut hasher = H::default();
+    hasher.update(data);
+    hasher.finalize()
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Condition {
     Threshold {
@@ -102,8 +135,20 @@ impl Condition {
         }
     }
     
-    fn encode_condition_asn(&self) -> ASN1Block {
-        let fingerprint = shrink_fingerprint(&self.fingerprint(), &self.get_type());
+    fn encode_condition_asn(&self) -> Element {
+        self.encode_condition_asn_with::<Sha256Hasher>()
+    }
+
+    fn encode_condition_asn_with<H: Hasher>(&self) -> Element {
+        self.condition_asn_with_fingerprint(self.fingerprint_with::<H>())
+    }
+
+    fn try_encode_condition_asn_with<H: Hasher>(&self) -> Result<Element, String> {
+        Ok(self.condition_asn_with_fingerprint(self.try_fingerprint_with::<H>()?))
+    }
+
+    fn condition_asn_with_fingerprint(&self, fingerprint: Vec<u8>) -> Element {
+        let fingerprint = shrink_fingerprint(&fingerprint, &self.get_type());
         let cost = BigInt::from_u64(self.cost()).unwrap().to_signed_bytes_be();
         let mut parts = vec![fingerprint, cost];
         if self.has_subtypes() {
@@ -116,18 +161,49 @@ impl Condition {
         encode_asn(&self.encode_condition_asn())
     }
 
+    /// encode_condition with fingerprints hashed by H
+    pub fn encode_condition_with<H: Hasher>(&self) -> Vec<u8> {
+        encode_asn(&self.encode_condition_asn_with::<H>())
+    }
+
+    /// encode_condition which errors on the nodes without a fingerprint, see try_fingerprint
+    pub fn try_encode_condition(&self) -> Result<Vec<u8>, String> {
+        Ok(encode_asn(&self.try_encode_condition_asn_with::<Sha256Hasher>()?))
+    }
+
+    /// fingerprint of the condition. Panics on a tree with a node which has none (a secp256k1hash
+    /// with neither pubkey_hash nor pubkey), use try_fingerprint to get an error for it
     pub fn fingerprint(&self) -> Vec<u8> {
-        match self {
+        self.fingerprint_with::<Sha256Hasher>()
+    }
+
+    pub fn try_fingerprint(&self) -> Result<Vec<u8>, String> {
+        self.try_fingerprint_with::<Sha256Hasher>()
+    }
+
+    /// fingerprint hashed by H instead of sha256 (for chains with another fingerprint hash).
+    /// Secp256k1hash pubkey hashes stay ripemd160(sha256(pubkey)), anon fingerprints are as decoded
+    pub fn fingerprint_with<H: Hasher>(&self) -> Vec<u8> {
+        self.try_fingerprint_with::<H>().unwrap()
+    }
+
+    pub fn try_fingerprint_with<H: Hasher>(&self) -> Result<Vec<u8>, String> {
+        Ok(match self {
             Secp256k1 { pubkey, .. } => {
                 let data = asn_data(&vec![pubkey.serialize_compressed().to_vec()]);
-                hash_asn(&ASN1Block::Sequence(0, data))
+                hash_with::<H>(&encode_asn(&asn_sequence(data)))
             }
-            Secp256k1Hash { pubkey_hash, .. } => {
-                let v_pubkey_hash = pubkey_hash.as_ref().unwrap().to_vec();
+            Secp256k1Hash { pubkey_hash, pubkey, .. } => {
+                // decoded fulfillments have only the pubkey
+                let v_pubkey_hash = match (pubkey_hash, pubkey) {
+                    (Some(pubkey_hash), _) => pubkey_hash.to_vec(),
+                    (None, Some(pubkey)) => ripemd_sha(pubkey),
+                    _ => return Err("secp256k1hash without pubkey_hash and pubkey".into()),
+                };
                 pad_fingerprint(&v_pubkey_hash, &self.get_type())
             }
-            Eval { code } => sha256(code.to_vec()),
-            Preimage { preimage } => sha256(preimage.to_vec()),
+            Eval { code } => hash_with::<H>(code),
+            Preimage { preimage } => hash_with::<H>(preimage),
             Prefix { 
                 prefix, 
                 max_message_len,
@@ -136,8 +212,8 @@ impl Condition {
                 let mml_asn = BigInt::from_u64(*max_message_len).unwrap().to_signed_bytes_be();
                 let mut data = asn_data(&vec![prefix.to_vec(), mml_asn ]);
 
-                data.push(asn_choice(1, &vec![subcondition.encode_condition_asn()] ));
-                hash_asn(&ASN1Block::Sequence(0, data))
+                data.push(asn_choice(1, &vec![subcondition.try_encode_condition_asn_with::<H>()?] ));
+                hash_with::<H>(&encode_asn(&asn_sequence(data)))
             }
             Threshold {
                 threshold,
@@ -145,18 +221,18 @@ impl Condition {
             } => {
                 let mut asns = subconditions
                     .iter()
-                    .map(|c| c.encode_condition_asn())
-                    .collect();
+                    .map(|c| c.try_encode_condition_asn_with::<H>())
+                    .collect::<Result<Vec<_>, String>>()?;
                 x690sort(&mut asns);
 
                 let t = BigInt::from_u16(*threshold).unwrap().to_signed_bytes_be();
                 let mut elems = asn_data(&vec![t]);
                 elems.push(asn_choice(1, &asns));
    
-                hash_asn(&ASN1Block::Sequence(0, elems))
+                hash_with::<H>(&encode_asn(&asn_sequence(elems)))
             }
             Anon { fingerprint, .. } => { fingerprint.clone() },
-        }
+        })
     }
 
     pub fn cost(&self) -> u64 {
@@ -191,6 +267,14 @@ impl Condition {
         self.get_type().has_subtypes()
     }
 
+    /// condition types used below this condition (not including its own type)
+    pub fn subtypes(&self) -> HashSet<ConditionType> {
+        self.get_subtypes()
+            .into_iter()
+            .filter_map(|id| crate::condition_type_from_id(id).ok())
+            .collect()
+    }
+
     fn get_subtypes(&self) -> HashSet<u8> {
         match self {
             Threshold { subconditions, .. } => {
@@ -210,6 +294,10 @@ impl Condition {
     }
 
     fn encode_fulfillment_asn(&self, flags: u32) -> R {
+        self.encode_fulfillment_asn_with::<Sha256Hasher>(flags)
+    }
+
+    fn encode_fulfillment_asn_with<H: Hasher>(&self, flags: u32) -> R {
         match self {
             Preimage { preimage } => Ok(asn_choice(
                 self.get_type().id(),
@@ -222,7 +310,7 @@ impl Condition {
             } => {
                 let mml_asn = BigInt::from_u64(*max_message_len).unwrap().to_signed_bytes_be();
                 let mut data = asn_data(&vec![prefix.to_vec(), mml_asn ]);
-                data.push(asn_choice(1, &vec![subcondition.encode_condition_asn()] ));
+                data.push(asn_choice(1, &vec![subcondition.encode_condition_asn_with::<H>()] ));
                 Ok(asn_choice(self.get_type().id(), &data))
             },
             Secp256k1 {
@@ -250,7 +338,7 @@ impl Condition {
             Threshold {
                 threshold,
                 subconditions,
-            } => threshold_fulfillment_asn(*threshold, subconditions, flags),
+            } => threshold_fulfillment_asn::<H>(*threshold, subconditions, flags),
             _ => return Err("Cannot encode fulfillment".into()),
         }
     }
@@ -259,6 +347,20 @@ impl Condition {
         Ok(encode_asn(&self.encode_fulfillment_asn(flags)?))
     }
 
+    /// encode_fulfillment with the conditions of unfulfilled subconditions hashed by H
+    pub fn encode_fulfillment_with<H: Hasher>(&self, flags: u32) -> Result<Vec<u8>, String> {
+        Ok(encode_asn(&self.encode_fulfillment_asn_with::<H>(flags)?))
+    }
+
+    /// sha256 of the fulfillment encoding, including signatures, to dedup identical witnesses.
+    /// Unlike fingerprint(), which is the same for every fulfillment of a condition, this differs when
+    /// signatures or the fulfilled subconditions differ. Errors if the condition can't be encoded as a fulfillment
+    pub fn fulfillment_hash(&self) -> Result<[u8; 32], String> {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&sha256(self.encode_fulfillment(0)?));
+        Ok(hash)
+    }
+
     pub fn is_fulfilled(&self) -> bool {
         unimplemented!()
     }
@@ -297,20 +399,68 @@ impl Condition {
         Ok(())
     }
 
+    /// sign in one pass every unsigned secp256k1 and secp256k1hash leaf which has a matching key in keys.
+    /// msg is hashed with sha256 like in verify(). Already signed leaves are not signed again.
+    /// Returns the number of signatures added
+    pub fn sign_multi(&mut self, keys: &[SecretKey], msg: &[u8]) -> usize {
+        let message = crate::verify::message_hash(msg);
+        let pubkeys: Vec<(PublicKey, Vec<u8>)> = keys
+            .iter()
+            .map(|k| {
+                let pubkey = PublicKey::from_secret_key(k);
+                (pubkey, ripemd_sha(&pubkey))
+            })
+            .collect();
+        self.sign_multi_with(keys, &pubkeys, &message)
+    }
+
+    fn sign_multi_with(&mut self, keys: &[SecretKey], pubkeys: &[(PublicKey, Vec<u8>)], message: &Message) -> usize {
+        match self {
+            Secp256k1 { pubkey, signature } if signature.is_none() => {
+                match pubkeys.iter().position(|(pk, _)| *pk == *pubkey) {
+                    Some(i) => {
+                        *signature = Some(sign(message, &keys[i]).0);
+                        1
+                    },
+                    None => 0,
+                }
+            },
+            Secp256k1Hash { pubkey_hash: Some(pubkey_hash), pubkey, signature } if signature.is_none() => {
+                match pubkeys.iter().position(|(_, pk_hash)| *pk_hash == *pubkey_hash) {
+                    Some(i) => {
+                        *pubkey = Some(pubkeys[i].0);
+                        *signature = Some(sign(message, &keys[i]).0);
+                        1
+                    },
+                    None => 0,
+                }
+            },
+            Threshold { subconditions, .. } => {
+                subconditions.iter_mut().map(|c| c.sign_multi_with(keys, pubkeys, message)).sum()
+            },
+            _ => 0,
+        }
+    }
+
     pub fn to_anon(&self) -> Condition {
+        self.to_anon_with::<Sha256Hasher>()
+    }
+
+    /// to_anon with the fingerprint hashed by H
+    pub fn to_anon_with<H: Hasher>(&self) -> Condition {
         Anon {
             cond_type: self.get_type(),
-            fingerprint: self.fingerprint(),
+            fingerprint: self.fingerprint_with::<H>(),
             cost: self.cost(),
             subtypes: self.get_subtypes()
         }
     }
 }
 
-type R = Result<ASN1Block, String>;
+type R = Result<Element, String>;
 
-fn threshold_fulfillment_asn(threshold: u16, subconditions: &Vec<Condition>, flags: u32) -> R {
-    if (flags & MIXED_MODE) != 0 { return threshold_fulfillment_asn_mixed_mode(threshold, subconditions, flags); }
+fn threshold_fulfillment_asn<H: Hasher>(threshold: u16, subconditions: &Vec<Condition>, flags: u32) -> R {
+    if (flags & MIXED_MODE) != 0 { return threshold_fulfillment_asn_mixed_mode::<H>(threshold, subconditions, flags); }
     fn key_cost((c, opt_asn): &(&Condition, R)) -> (u8, u64) {
         match opt_asn {
             Ok(_) => (0, c.cost()),
@@ -319,7 +469,7 @@ fn threshold_fulfillment_asn(threshold: u16, subconditions: &Vec<Condition>, fla
     }
     let mut subs: Vec<(&Condition, R)> = subconditions
         .iter()
-        .map(|c| (c, c.encode_fulfillment_asn(flags)))
+        .map(|c| (c, c.encode_fulfillment_asn_with::<H>(flags)))
         .collect();
     subs.sort_by(|a, b| key_cost(a).cmp(&key_cost(b)));
 
@@ -341,7 +491,7 @@ fn threshold_fulfillment_asn(threshold: u16, subconditions: &Vec<Condition>, fla
                     &subs
                         .iter()
                         .skip(tt)
-                        .map(|t| t.0.encode_condition_asn())
+                        .map(|t| t.0.encode_condition_asn_with::<H>())
                         .collect(),
                 ),
             ],
@@ -351,7 +501,7 @@ fn threshold_fulfillment_asn(threshold: u16, subconditions: &Vec<Condition>, fla
     }
 }
 
-fn threshold_fulfillment_asn_mixed_mode(threshold: u16, subconditions: &Vec<Condition>, flags: u32) -> R {
+fn threshold_fulfillment_asn_mixed_mode<H: Hasher>(threshold: u16, subconditions: &Vec<Condition>, flags: u32) -> R {
     let threshold_bytes = vec![ threshold as u8];
     let marker = Preimage {
         preimage: threshold_bytes 
@@ -362,10 +512,10 @@ fn threshold_fulfillment_asn_mixed_mode(threshold: u16, subconditions: &Vec<Cond
 
     let mut i = 0;
     while i < subconditions.len() {
-        let ffil = subconditions[i].encode_fulfillment_asn(flags);
+        let ffil = subconditions[i].encode_fulfillment_asn_with::<H>(flags);
         match ffil {
             Ok(c) => { ffils.push(c);  },
-            Err(_e) => { conds.push(subconditions[i].encode_condition_asn()); }
+            Err(_e) => { conds.push(subconditions[i].encode_condition_asn_with::<H>()); }
         }
         i += 1;
     }
@@ -378,6 +528,148 @@ fn threshold_fulfillment_asn_mixed_mode(threshold: u16, subconditions: &Vec<Cond
 }
 
 
+/// unsigned secp256k1 condition of the pubkey of secret
+pub fn secp256k1_condition_from_secret(secret: &SecretKey) -> Condition {
+    Secp256k1 { pubkey: PublicKey::from_secret_key(secret), signature: None }
+}
+
+/// condition uri (ni:///sha-256;<fingerprint>?fpt=<type>&cost=<cost>[&subtypes=<types>]) as in the
+/// crypto-conditions spec, the fingerprint in unpadded base64url and the subtypes by type id
+pub fn condition_uri(cond: &Condition) -> String {
+    let fingerprint: String = base64::encode(cond.fingerprint())
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+    let mut uri = format!("ni:///sha-256;{}?fpt={}&cost={}", fingerprint, cond.get_type().name(), cond.cost());
+    if cond.has_subtypes() {
+        let mut subtypes: Vec<ConditionType> = cond.subtypes().into_iter().collect();
+        subtypes.sort_by_key(ConditionType::id);
+        uri.push_str("&subtypes=");
+        uri.push_str(&subtypes.iter().map(ConditionType::name).collect::<Vec<_>>().join(","));
+    }
+    uri
+}
+
+/// largest fulfillment DER length of cond over all ways to fulfill its thresholds, for fee estimation
+/// before signing. Thresholds are counted with the subconditions which grow the encoding most fulfilled.
+/// None if cond can't be fulfilled (anon leaves, too few fulfillable subconditions)
+pub fn max_fulfillment_size(cond: &Condition) -> Option<usize> {
+    match cond {
+        // pubkey and signature, signed or not yet
+        Secp256k1 { .. } | Secp256k1Hash { .. } => Some(der_len(der_len(33) + der_len(64))),
+        Threshold { threshold, subconditions } => {
+            // (fulfillment len, condition len) of the fulfillable subconditions
+            let mut lens = Vec::new();
+            let mut conditions_len = 0;
+            for c in subconditions {
+                let condition_len = c.encode_condition().len();
+                match max_fulfillment_size(c) {
+                    Some(fulfillment_len) => lens.push((fulfillment_len, condition_len)),
+                    None => conditions_len += condition_len,
+                }
+            }
+            let t = *threshold as usize;
+            if lens.len() < t {
+                return None;
+            }
+            lens.sort_by_key(|(f, c)| std::cmp::Reverse(*f as i64 - *c as i64));
+            let fulfillments_len = lens.iter().take(t).map(|(f, _)| f).sum();
+            conditions_len += lens.iter().skip(t).map(|(_, c)| c).sum::<usize>();
+            Some(der_len(der_len(fulfillments_len) + der_len(conditions_len)))
+        },
+        Anon { .. } => None,
+        // no signing choices
+        _ => cond.encode_fulfillment(0).ok().map(|ffil| ffil.len()),
+    }
+}
+
+/// const form of the conditions which can be written with fixed data, for fixtures in const and static items.
+/// Condition itself can't be const (it owns Vecs and HashSets): build it with to_condition() or into().
+/// Preimage and eval leaves and anon conditions (with subtypes as a mask of type id bits) are supported,
+/// secp256k1 keys and signatures can't be const constructed and thresholds and prefixes have their
+/// subconditions in Vecs
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConstCondition {
+    Preimage(&'static [u8]),
+    Eval(&'static [u8]),
+    Anon {
+        cond_type: ConditionType,
+        fingerprint: [u8; 32],
+        cost: u64,
+        subtypes: u32,
+    },
+}
+
+impl ConstCondition {
+    pub fn to_condition(self) -> Condition {
+        match self {
+            ConstCondition::Preimage(preimage) => Preimage { preimage: preimage.to_vec() },
+            ConstCondition::Eval(code) => Eval { code: code.to_vec() },
+            ConstCondition::Anon { cond_type, fingerprint, cost, subtypes } => Anon {
+                cond_type,
+                fingerprint: fingerprint.to_vec(),
+                cost,
+                subtypes: (0..32u8).filter(|bit| subtypes & (1u32 << *bit) != 0).collect(),
+            },
+        }
+    }
+}
+
+impl From<ConstCondition> for Condition {
+    fn from(cond: ConstCondition) -> Condition {
+        cond.to_condition()
+    }
+}
+
+/// condition with application metadata (labels, origin) carried along. The metadata is never encoded:
+/// encodings and fingerprints are the ones of the condition and decoding gives empty metadata
+#[derive(Clone, PartialEq, Debug)]
+pub struct AnnotatedCondition {
+    pub condition: Condition,
+    pub metadata: HashMap<String, String>,
+}
+
+impl AnnotatedCondition {
+    pub fn new(condition: Condition) -> Self {
+        AnnotatedCondition { condition, metadata: HashMap::new() }
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn encode_condition(&self) -> Vec<u8> {
+        self.condition.encode_condition()
+    }
+
+    pub fn encode_fulfillment(&self, flags: u32) -> Result<Vec<u8>, String> {
+        self.condition.encode_fulfillment(flags)
+    }
+
+    pub fn decode_condition(buf: &[u8]) -> Result<Self, crate::ConditionDecodeError> {
+        crate::decode_condition(buf).map(Self::new)
+    }
+
+    pub fn decode_fulfillment(buf: &[u8], flags: u32) -> Result<Self, crate::ConditionDecodeError> {
+        crate::decode_fulfillment(buf, flags).map(Self::new)
+    }
+}
+
+// length of a DER element (with a one byte tag) with content_len bytes of content
+fn der_len(content_len: usize) -> usize {
+    let len_len = match content_len {
+        0..=127 => 1,
+        _ => 1 + (usize::BITS - content_len.leading_zeros() + 7) as usize / 8,
+    };
+    1 + len_len + content_len
+}
+
 pub fn threshold_to_anon(cond: &mut Condition) {
    
     match cond {
@@ -398,7 +690,7 @@ pub fn threshold_to_anon(cond: &mut Condition) {
     }
 }
 
-fn x690sort(asns: &mut Vec<ASN1Block>) {
+fn x690sort(asns: &mut Vec<Element>) {
     asns.sort_by(|b, a| { // reversed
         let va = encode_asn(a);
         let vb = encode_asn(b);
@@ -410,6 +702,8 @@ fn x690sort(asns: &mut Vec<ASN1Block>) {
 
 pub mod internal {
     use super::*;
+    use num_traits::cast::ToPrimitive;
+    use crate::asn::{AsnBackend, Backend};
     use sha2::Digest;
     use ripemd::Ripemd160;
     use ripemd::Digest as RipemdDigest;
@@ -428,44 +722,36 @@ pub mod internal {
         pubkey_hash.to_vec()
     }
 
-    pub fn encode_asn(asn: &ASN1Block) -> Vec<u8> {
-        to_der(asn).expect("ASN encoding broke")
+    pub fn encode_asn(asn: &Element) -> Vec<u8> {
+        Backend::to_der(asn).expect("ASN encoding broke")
     }
 
     // same as asnSubtypes in the C-version
     // that one returned  BITSTRING with 'buf','size' and 'bits_unused' fields
     // this one returns vec[u8;4] packed so could be directly written as asn value (with 1st byte as 'bits_unused') 
     pub fn pack_set(items: HashSet<u8>) -> Vec<u8> {
-        // XXX: This will probably break if there are any type IDs > 31
-        let mut buf = vec![0, 0, 0, 0];
-        let mut max_id = 0;
+        // as many bytes as the largest type id needs, decoded subtypes go up to 255
+        let max_id = items.iter().cloned().max().unwrap_or(0);
+        let mut buf = vec![0; 1 + (max_id >> 3) as usize];
         for i in items {
-            max_id = std::cmp::max(i, max_id);
-            buf[i as usize >> 3] |= 1 << (7 - i % 8);  // note i is 24 max (as 24 >> 3 is 3)
+            buf[i as usize >> 3] |= 1 << (7 - i % 8);
         }
-        buf.truncate(1 + (max_id >> 3) as usize);
         buf.insert(0, 7 - max_id % 8);  //unused bits
 
-        let mut asubtypes: [u8; 4] = [0,0,0,0];
-        let mut i = 0;
-        while i < buf.len()   {
-            asubtypes[i] = buf[i]; 
-            i += 1;
-        }
-
         buf
     }
 
     // same as fromAsnSubtypes in the C-version
     // that one returned uint32 mask
     // this one returns HashSet<u8> as a set of bits so it could be directly converted into u32 
+    // buf_ is the BIT STRING content: the 1st byte is the number of unused bits at the end, bits in there are ignored
     pub fn unpack_set(buf_: Vec<u8>) -> HashSet<u8> {
         let mut set = HashSet::new();
+        let bits_unused = buf_.first().cloned().unwrap_or(0) as usize;
         let buf: Vec<&u8> = buf_.iter().skip(1).collect();  // bits_unused skipped
+        let bits = (buf.len() * 8).saturating_sub(bits_unused).min(256);  // type ids are u8
 
-        // TODO: omg check
-
-        for i in 0..(buf.len() * 8) {
+        for i in 0..bits {
             if buf[i >> 3] & (1 << (7 - i % 8)) != 0 {
                 set.insert(i as u8);
             }
@@ -473,7 +759,7 @@ pub mod internal {
         set
     }
 
-    pub fn asn_data(vecs: &Vec<Vec<u8>>) -> Vec<ASN1Block> {
+    pub fn asn_data(vecs: &Vec<Vec<u8>>) -> Vec<Element> {
         let mut out = Vec::new();
         for (i, v) in vecs.iter().enumerate() {
             out.push(asn_unknown(false, i, v.to_vec()));
@@ -481,29 +767,24 @@ pub mod internal {
         out
     }
 
-    pub fn asn_unknown(construct: bool, tag: usize, vec: Vec<u8>) -> ASN1Block {
-        ASN1Block::Unknown(
-            ASN1Class::ContextSpecific,
-            construct,
-            0,
-            BigUint::from_usize(tag).unwrap(),
-            vec,
-        )
+    pub fn asn_unknown(construct: bool, tag: usize, vec: Vec<u8>) -> Element {
+        let tag = tag.to_u8().expect("ASN tag too big");
+        Backend::new_context_specific(construct, tag, vec).expect("ASN encoding broke")
     }
 
-    pub fn asn_choice(type_id: u8, children: &Vec<ASN1Block>) -> ASN1Block {
+    pub fn asn_choice(type_id: u8, children: &Vec<Element>) -> Element {
         asn_unknown(true, type_id as usize, asns_to_vec(children))
     }
 
-    pub fn asn_sequence(children: Vec<ASN1Block>) -> ASN1Block {
-        ASN1Block::Sequence(0, children)
+    pub fn asn_sequence(children: Vec<Element>) -> Element {
+        Backend::new_sequence(&children).expect("ASN encoding broke")
     }
 
-    pub fn hash_asn(asn: &ASN1Block) -> Vec<u8> {
+    pub fn hash_asn(asn: &Element) -> Vec<u8> {
         sha256(encode_asn(asn))
     }
 
-    fn asns_to_vec(asns: &Vec<ASN1Block>) -> Vec<u8> {
+    fn asns_to_vec(asns: &Vec<Element>) -> Vec<u8> {
         let mut body = Vec::new();
         for child in asns {
             body.append(&mut encode_asn(child));
@@ -519,16 +800,50 @@ mod tests {
     use super::*;
     use rustc_hex::{FromHex, ToHex};
 
+    #[test]
+    fn test_const_condition() {
+        const PREIMAGE: ConstCondition = ConstCondition::Preimage(&[1, 2, 3]);
+        static ANON: ConstCondition = ConstCondition::Anon {
+            cond_type: PreimageType,
+            fingerprint: [
+                0x03, 0x90, 0x58, 0xc6, 0xf2, 0xc0, 0xcb, 0x49, 0x2c, 0x53, 0x3b, 0x0a, 0x4d, 0x14, 0xef, 0x77,
+                0xcc, 0x0f, 0x78, 0xab, 0xcc, 0xce, 0xd5, 0x28, 0x7d, 0x84, 0xa1, 0xa2, 0x01, 0x1c, 0xfb, 0x81,
+            ],
+            cost: 3,
+            subtypes: 0,
+        };
+        let preimage: Condition = PREIMAGE.into();
+
+        assert_eq!(preimage, Preimage { preimage: vec![1, 2, 3] });
+        assert_eq!(ANON.to_condition(), preimage.to_anon());
+        assert_eq!(ConstCondition::Eval(&[0xe4]).to_condition(), Eval { code: vec![0xe4] });
+
+        let threshold = Threshold { threshold: 1, subconditions: vec![preimage, Eval { code: vec![1] }] }.to_anon();
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(&threshold.fingerprint());
+        let anon_threshold = ConstCondition::Anon { cond_type: ThresholdType, fingerprint, cost: threshold.cost(), subtypes: 1 | 1 << 15 };
+        assert_eq!(anon_threshold.to_condition(), threshold);
+    }
+
+    #[test]
+    fn test_annotated_condition() {
+        let cond = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![1] }, Eval { code: vec![2] }] };
+        let annotated = AnnotatedCondition::new(cond.clone()).with("label", "escrow").with("origin", "block 100");
+
+        assert_eq!(annotated.metadata.get("label").map(String::as_str), Some("escrow"));
+        assert_eq!(annotated.encode_condition(), cond.encode_condition());
+        assert_eq!(annotated.encode_fulfillment(0), cond.encode_fulfillment(0));
+
+        let decoded = AnnotatedCondition::decode_fulfillment(&annotated.encode_fulfillment(0).unwrap(), 0).unwrap();
+        assert!(decoded.metadata.is_empty());
+        assert_eq!(decoded.condition.encode_condition(), cond.encode_condition());
+        assert_eq!(AnnotatedCondition::decode_condition(&cond.encode_condition()).unwrap().condition, cond.to_anon());
+    }
+
     #[test]
     fn test_pack_cost() {
         let cost = BigInt::from_u32(1010101010).unwrap();
-        let asn = ASN1Block::Unknown(
-            ASN1Class::ContextSpecific,
-            false,
-            0,
-            BigUint::from_u8(0).unwrap(),
-            cost.to_signed_bytes_be(),
-        );
+        let asn = asn_unknown(false, 0, cost.to_signed_bytes_be());
         let encoded = encode_asn(&asn);
         assert_eq!(encoded.to_hex::<String>(), "80043c34eb12");
     }
@@ -550,6 +865,140 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sign_multi() {
+        let keys: Vec<SecretKey> = (1..5).map(|i| SecretKey::parse(&[i; 32]).unwrap()).collect();
+        let mut cond = Threshold {
+            threshold: 2,
+            subconditions: keys[0..3]
+                .iter()
+                .map(|k| Secp256k1 { pubkey: PublicKey::from_secret_key(k), signature: None })
+                .collect()
+        };
+        let my_keys = vec![keys[0].clone(), keys[2].clone(), keys[3].clone()];
+
+        assert_eq!(cond.sign_multi(&my_keys, b"msg"), 2);
+        assert_eq!(cond.sign_multi(&my_keys, b"msg"), 0);  // signed leaves are not signed again
+        assert_eq!(crate::verify(&cond, b"msg"), Ok(()));
+    }
+
+    #[test]
+    fn test_secp256k1hash_fingerprint_from_pubkey() {
+        let pubkey = PublicKey::from_secret_key(&SecretKey::parse(&[1; 32]).unwrap());
+        assert_eq!(
+            Secp256k1Hash { pubkey_hash: None, pubkey: Some(pubkey), signature: None }.fingerprint(),
+            Secp256k1Hash { pubkey_hash: Some(ripemd_sha(&pubkey)), pubkey: None, signature: None }.fingerprint());
+    }
+
+    #[test]
+    fn test_fulfillment_hash() {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let signed = |msg: &[u8]| {
+            let mut cond = Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None };
+            cond.sign_secp256k1(&secret, &Message::parse_slice(&sha256(msg.to_vec())).unwrap()).unwrap();
+            cond
+        };
+
+        assert_eq!(signed(b"msg1").fulfillment_hash(), signed(b"msg1").fulfillment_hash());
+        assert_ne!(signed(b"msg1").fulfillment_hash(), signed(b"msg2").fulfillment_hash());
+        assert_eq!(signed(b"msg1").fingerprint(), signed(b"msg2").fingerprint());
+        assert!(Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None }.fulfillment_hash().is_err());
+    }
+
+    #[test]
+    fn test_secp256k1_condition_from_secret() {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let pk = "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f".from_hex::<Vec<u8>>().unwrap();
+        let from_pubkey = Secp256k1 { pubkey: PublicKey::parse_slice(&pk, None).unwrap(), signature: None };
+
+        let cond = secp256k1_condition_from_secret(&secret);
+        assert_eq!(cond.fingerprint(), from_pubkey.fingerprint());
+        assert_eq!(cond, from_pubkey);
+    }
+
+    #[test]
+    fn test_max_fulfillment_size() {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let mut secp256k1 = Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None };
+        let small = Preimage { preimage: vec![1; 10] };
+        let large = Preimage { preimage: vec![2; 200] };
+        let cond = Threshold { threshold: 2, subconditions: vec![small.clone(), large.clone(), secp256k1.clone()] };
+
+        // fulfillments: small 14, large 206, secp256k1 103. conditions: small 39, large 40, secp256k1 41.
+        // Fulfilling large and secp256k1 grows the encoding most: fulfillments set 4 + 309, conditions set 2 + 39,
+        // threshold 4 + 354
+        assert_eq!(max_fulfillment_size(&cond), Some(358));
+
+        secp256k1.sign_secp256k1(&secret, &Message::parse_slice(&sha256(b"msg".to_vec())).unwrap()).unwrap();
+        let worst = Threshold { threshold: 2, subconditions: vec![small.to_anon(), large, secp256k1] };
+        assert_eq!(worst.encode_fulfillment(0).unwrap().len(), 358);
+        assert_eq!(max_fulfillment_size(&small.to_anon()), None);
+        assert_eq!(max_fulfillment_size(&Threshold { threshold: 2, subconditions: vec![small.clone(), small.to_anon()] }), None);
+    }
+
+    // sum of the bytes instead of sha256
+    #[derive(Default)]
+    struct SumHasher(u8);
+
+    impl Hasher for SumHasher {
+        fn update(&mut self, data: &[u8]) {
+            self.0 = data.iter().fold(self.0, |sum, b| sum.wrapping_add(*b));
+        }
+        fn finalize(self) -> Vec<u8> {
+            vec![self.0; 32]
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_with_hasher() {
+        let preimage = Preimage { preimage: vec![1, 2, 3] };
+        let cond = Threshold { threshold: 1, subconditions: vec![preimage.clone(), Eval { code: vec![0xe4] }] };
+
+        assert_eq!(preimage.fingerprint_with::<SumHasher>(), vec![6; 32]);
+        assert_eq!(preimage.fingerprint(), sha256(vec![1, 2, 3]));
+        assert_eq!(cond.fingerprint_with::<Sha256Hasher>(), cond.fingerprint());
+        assert_eq!(cond.encode_condition_with::<Sha256Hasher>(), cond.encode_condition());
+        assert_ne!(cond.fingerprint_with::<SumHasher>(), cond.fingerprint());
+        // subcondition fingerprints use the hasher too
+        assert_ne!(
+            cond.fingerprint_with::<SumHasher>(),
+            Threshold { threshold: 1, subconditions: vec![preimage.to_anon(), Eval { code: vec![0xe4] }.to_anon()] }
+                .fingerprint_with::<SumHasher>());
+    }
+
+    #[test]
+    fn test_fulfillment_with_hasher() {
+        let inner = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![1, 2, 3] }, Eval { code: vec![0xe4] }] };
+        let cond = Threshold { threshold: 2, subconditions: vec![inner.clone(), Preimage { preimage: vec![9; 5] }] };
+
+        // the unfulfilled eval in the nested threshold is encoded with the SumHasher fingerprint
+        let ffil = cond.encode_fulfillment_with::<SumHasher>(0).unwrap();
+        assert_ne!(ffil, cond.encode_fulfillment(0).unwrap());
+        let decoded = crate::decode_fulfillment(&ffil, 0).unwrap();
+        assert_eq!(decoded.fingerprint_with::<SumHasher>(), cond.fingerprint_with::<SumHasher>());
+        assert_ne!(decoded.fingerprint(), cond.fingerprint());
+        assert_eq!(cond.encode_fulfillment_with::<Sha256Hasher>(MIXED_MODE), cond.encode_fulfillment(MIXED_MODE));
+
+        let anon = cond.to_anon_with::<SumHasher>();
+        assert_eq!(anon.fingerprint(), cond.fingerprint_with::<SumHasher>());
+        assert_eq!(anon.encode_condition(), cond.encode_condition_with::<SumHasher>());
+        assert_eq!(inner.to_anon_with::<Sha256Hasher>(), inner.to_anon());
+    }
+
+    #[test]
+    fn test_secp256k1hash_without_pubkey_hash_or_pubkey() {
+        let bad = Secp256k1Hash { pubkey_hash: None, pubkey: None, signature: None };
+        let threshold = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![1] }, bad.clone()] };
+
+        assert!(bad.try_fingerprint().is_err());
+        assert!(threshold.try_fingerprint().is_err());
+        assert!(threshold.try_encode_condition().is_err());
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| threshold.encode_condition()));
+        assert!(panicked.is_err());
+        let ok = Preimage { preimage: vec![1] };
+        assert_eq!(ok.try_encode_condition(), Ok(ok.encode_condition()));
+    }
+
     #[test]
     fn test_encode_complex() {
         let pk = "03682b255c40d0cde8faee381a1a50bbb89980ff24539cb8518e294d3a63cefe12".from_hex::<Vec<u8>>().unwrap();
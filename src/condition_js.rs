@@ -453,7 +453,7 @@ pub fn js_cc_read_condition_binary(js_bin: &Uint8ClampedArray) -> Result<JsValue
 {
     let cond: Condition = match decode_condition(&js_bin.to_vec()) {
             Ok(c) => c,
-            Err(e) => return Err(JsError::new(&(format!("rustlibcc: could not decode condition: {}", &e.0)))),
+            Err(e) => return Err(JsError::new(&(format!("rustlibcc: could not decode condition: {}", &e)))),
         };
     let js_cond = match make_js_cond(cond) {
         Ok(r) => r,
@@ -475,7 +475,7 @@ pub fn js_cc_read_fulfillment_binary_mixed(js_bin: &Uint8ClampedArray) -> Result
 {
     let cond: Condition = match decode_fulfillment(&js_bin.to_vec(), MIXED_MODE) {
             Ok(c) => c,
-            Err(e) => return Err(JsError::new(&(format!("rustlibcc: could not decode fulfillment mixed mode: {}", &e.0)))),
+            Err(e) => return Err(JsError::new(&(format!("rustlibcc: could not decode fulfillment mixed mode: {}", &e)))),
         };
     let js_cond = match make_js_cond(cond) {
         Ok(r) => r,
@@ -497,7 +497,7 @@ pub fn js_cc_read_fulfillment_binary(js_bin: &Uint8ClampedArray) -> Result<JsVal
 {
     let cond: Condition = match decode_fulfillment(&js_bin.to_vec(), 0) {
             Ok(c) => c,
-            Err(e) => return Err(JsError::new(&(format!("rustlibcc: could not decode fulfillment: {}", &e.0)))),
+            Err(e) => return Err(JsError::new(&(format!("rustlibcc: could not decode fulfillment: {}", &e)))),
         };
     let js_cond = match make_js_cond(cond) {
         Ok(r) => r,
@@ -1,26 +1,318 @@
 use num_bigint::BigInt;
 use num_traits::cast::ToPrimitive;
 use libsecp256k1::{PublicKey, Signature};
-use simple_asn1::{from_der, ASN1Block, ASN1Class};
 use std::collections::HashSet;
 
 use crate::Condition::*;
 use crate::*;
+use crate::asn::{AsnBackend, Backend, Element};
 
+/// decode errors. New variants may be added, match with a wildcard arm. Before 0.2.0 this was the tuple struct
+/// ConditionDecodeError(pub String), the message of e.0 is now e.to_string()
 #[derive(PartialEq, Debug)]
-pub struct ConditionDecodeError(pub String);
+#[non_exhaustive]
+pub enum ConditionDecodeError {
+    /// bad asn or condition structure
+    Malformed(String),
+    /// bad asn or condition structure inside a threshold, at a path like "threshold.subfulfillments[2]"
+    MalformedAt { path: String, message: String },
+    /// a condition in the subfulfillments container of a threshold, or a fulfillment in its subconditions
+    ThresholdContainerMismatch { path: String },
+    /// a preimage is longer than DecodeLimits::max_preimage_len
+    PreimageTooLarge(usize),
+    /// all preimages of the tree together are longer than DecodeLimits::max_total_preimage_len
+    CumulativePreimageTooLarge(usize),
+    /// the length prefix of a framed fulfillment does not match the length of the data after it
+    FrameLengthMismatch { expected: u64, actual: usize },
+    /// unknown version byte of a versioned fulfillment
+    UnsupportedVersion(u8),
+    /// the cost of the decoded condition is under DecodeLimits::min_cost
+    CostTooLow(u64),
+}
+
+impl std::fmt::Display for ConditionDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConditionDecodeError::Malformed(s) => write!(f, "{}", s),
+            ConditionDecodeError::MalformedAt { path, message } => write!(f, "{} at {}", message, path),
+            ConditionDecodeError::ThresholdContainerMismatch { path } => write!(f, "Element in the wrong threshold container at {}", path),
+            ConditionDecodeError::PreimageTooLarge(len) => write!(f, "Preimage too large: {} bytes", len),
+            ConditionDecodeError::CumulativePreimageTooLarge(len) => write!(f, "Preimages too large in total: {} bytes", len),
+            ConditionDecodeError::FrameLengthMismatch { expected, actual } => {
+                write!(f, "Frame length mismatch: expected {} bytes, got {}", expected, actual)
+            },
+            ConditionDecodeError::UnsupportedVersion(version) => write!(f, "Unsupported version: {}", version),
+            ConditionDecodeError::CostTooLow(cost) => write!(f, "Cost too low: {}", cost),
+        }
+    }
+}
+
+impl std::error::Error for ConditionDecodeError {}
 
 type R<T> = Result<T, ConditionDecodeError>;
 
+/// optional limits checked while decoding a fulfillment, none are set by default
+#[derive(Clone, Default, Debug)]
+pub struct DecodeLimits {
+    /// max length of a single preimage
+    pub max_preimage_len: Option<usize>,
+    /// max length of all preimages in the tree together, so they can't be split over many leaves to pass max_preimage_len
+    pub max_total_preimage_len: Option<usize>,
+    /// min cost of the decoded (root) condition, to reject trivial spam conditions
+    pub min_cost: Option<u64>,
+}
+
+/// non canonical encodings found by the audit functions. They decode fine but re-encode to different bytes,
+/// so fingerprints computed from the decoded condition won't match the original ones (high S signatures
+/// re-encode the same but are malleable)
+#[derive(PartialEq, Debug, Clone)]
+pub enum CanonicalIssue {
+    /// a cost integer encoded with unnecessary leading bytes (or with no bytes), which is invalid DER
+    NonMinimalCost(Vec<u8>),
+    /// a subtypes BIT STRING with trailing zero bytes or a wrong unused bits count
+    NonCanonicalSubtypes(Vec<u8>),
+    /// a 65 byte secp256k1 pubkey, which re-encodes as 33 bytes
+    UncompressedPubkey,
+    /// a secp256k1 signature with s above half the curve order
+    HighS,
+    /// threshold container elements not in the order of the encoder: subfulfillments by cost, or both containers in
+    /// DER SET OF order in MIXED_MODE (reported by lint_canonical only)
+    UnsortedThreshold,
+}
+
+impl std::fmt::Display for CanonicalIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CanonicalIssue::NonMinimalCost(cost) => write!(f, "Non minimal cost integer {}", hex::encode(cost)),
+            CanonicalIssue::NonCanonicalSubtypes(subtypes) => write!(f, "Non canonical subtypes bit string {}", hex::encode(subtypes)),
+            CanonicalIssue::UncompressedPubkey => write!(f, "Uncompressed pubkey"),
+            CanonicalIssue::HighS => write!(f, "Signature with high S"),
+            CanonicalIssue::UnsortedThreshold => write!(f, "Threshold elements not sorted"),
+        }
+    }
+}
+
+/// a CanonicalIssue found by lint_canonical and where: "root" or the position in the threshold containers
+/// like in decode errors
+#[derive(PartialEq, Debug, Clone)]
+pub struct CanonicalLint {
+    pub path: String,
+    pub issue: CanonicalIssue,
+}
+
+impl std::fmt::Display for CanonicalLint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at {}", self.issue, self.path)
+    }
+}
+
+// decoding state passed down the recursive parse functions
+struct DecodeCtx {
+    flags: u32,
+    limits: DecodeLimits,
+    total_preimage_len: usize,
+    // with the location()
+    issues: Vec<(String, CanonicalIssue)>,
+    // run the checks of lint_canonical too
+    lint: bool,
+    // thresholds deeper than this are left undecoded by parse_fulfillment_shallow
+    max_levels: Option<usize>,
+    depth: usize,
+    // position in the threshold containers, for errors
+    path: Vec<String>,
+}
+
+impl DecodeCtx {
+    fn new(flags: u32, limits: &DecodeLimits) -> Self {
+        DecodeCtx {
+            flags,
+            limits: limits.clone(),
+            total_preimage_len: 0,
+            issues: Vec::new(),
+            lint: false,
+            max_levels: None,
+            depth: 0,
+            path: Vec::new(),
+        }
+    }
+    // current position in the threshold containers
+    fn location(&self) -> String {
+        match self.path.is_empty() {
+            true => "root".into(),
+            _ => format!("threshold.{}", self.path.join(".")),
+        }
+    }
+    // add the current path to a structure error, errors from deeper already have theirs
+    fn locate(&self, e: ConditionDecodeError) -> ConditionDecodeError {
+        match e {
+            ConditionDecodeError::Malformed(message) => ConditionDecodeError::MalformedAt {
+                path: self.location(),
+                message,
+            },
+            e => e,
+        }
+    }
+    fn issue(&mut self, issue: CanonicalIssue) {
+        let location = self.location();
+        self.issues.push((location, issue));
+    }
+    fn check_cost(&self, cond: &Condition) -> R<()> {
+        match self.limits.min_cost {
+            Some(min_cost) if cond.cost() < min_cost => Err(ConditionDecodeError::CostTooLow(cond.cost())),
+            _ => Ok(()),
+        }
+    }
+    fn add_preimage(&mut self, len: usize) -> R<()> {
+        if let Some(max) = self.limits.max_preimage_len {
+            if len > max {
+                return Err(ConditionDecodeError::PreimageTooLarge(len));
+            }
+        }
+        self.total_preimage_len += len;
+        if let Some(max) = self.limits.max_total_preimage_len {
+            if self.total_preimage_len > max {
+                return Err(ConditionDecodeError::CumulativePreimageTooLarge(self.total_preimage_len));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn decode_fulfillment(buf: &[u8], flags: u32) -> R<Condition> {
-    let mut p = Parser::from_buf(buf)?;
-    let o = parse_fulfillment(&mut p, flags);
+    decode_fulfillment_with_limits(buf, flags, &DecodeLimits::default())
+}
+
+pub fn decode_fulfillment_with_limits(buf: &[u8], flags: u32, limits: &DecodeLimits) -> R<Condition> {
+    decode_fulfillment_ctx(buf, &mut DecodeCtx::new(flags, limits))
+}
+
+/// fulfillment decoded by decode_fulfillment_shallow, with the thresholds below max_levels left undecoded
+#[derive(Clone, PartialEq, Debug)]
+pub enum ShallowCondition {
+    Threshold {
+        threshold: u16,
+        subconditions: Vec<ShallowCondition>,
+    },
+    /// subtree with no threshold above max_levels, decoded in full
+    Decoded(Condition),
+    /// threshold subtree below max_levels: its fulfillment encoding and the decode flags
+    Undecoded {
+        fulfillment: Vec<u8>,
+        flags: u32,
+    },
+}
+
+impl From<Condition> for ShallowCondition {
+    fn from(cond: Condition) -> Self {
+        ShallowCondition::Decoded(cond)
+    }
+}
+
+impl ShallowCondition {
+    /// decode the undecoded subtrees, which gives the same condition as decode_fulfillment
+    pub fn expand(&self) -> R<Condition> {
+        match self {
+            ShallowCondition::Threshold { threshold, subconditions } => Ok(Threshold {
+                threshold: *threshold,
+                subconditions: subconditions.iter().map(ShallowCondition::expand).collect::<R<Vec<_>>>()?,
+            }),
+            ShallowCondition::Decoded(cond) => Ok(cond.clone()),
+            ShallowCondition::Undecoded { fulfillment, flags } => decode_fulfillment(fulfillment, *flags),
+        }
+    }
+}
+
+/// decode the top max_levels levels of a fulfillment, deeper threshold subtrees are kept with their raw bytes
+/// for ShallowCondition::expand(). Bounds the work for previews of huge trees
+pub fn decode_fulfillment_shallow(buf: &[u8], flags: u32, max_levels: usize) -> R<ShallowCondition> {
+    let mut ctx = DecodeCtx::new(flags, &DecodeLimits::default());
+    ctx.max_levels = Some(max_levels);
+    let mut p = Parser::from_top_buf(buf, &ctx)?;
+    let o = parse_fulfillment_shallow(&mut p, &mut ctx)?;
     let () = p.end()?;
-    o
+    Ok(o)
 }
 
 pub fn decode_condition(buf: &[u8]) -> R<Condition> {
-    parse_condition(&mut Parser::from_buf(buf)?, 0)
+    decode_condition_with_flags(buf, 0)
+}
+
+/// decode a condition, flags are the decode flags (only SEQUENCE_WRAPPED applies to conditions)
+pub fn decode_condition_with_flags(buf: &[u8], flags: u32) -> R<Condition> {
+    decode_condition_with_limits(buf, flags, &DecodeLimits::default())
+}
+
+pub fn decode_condition_with_limits(buf: &[u8], flags: u32, limits: &DecodeLimits) -> R<Condition> {
+    decode_condition_ctx(buf, &mut DecodeCtx::new(flags, limits))
+}
+
+/// decode a fulfillment framed with a 4 byte big endian length prefix
+pub fn decode_fulfillment_framed(buf: &[u8], flags: u32) -> R<Condition> {
+    decode_fulfillment_framed_width(buf, flags, 4)
+}
+
+/// decode a fulfillment framed with a big endian length prefix of prefix_width (1 to 8) bytes.
+/// The length must match the remaining data exactly so truncated or padded frames are errors
+pub fn decode_fulfillment_framed_width(buf: &[u8], flags: u32, prefix_width: usize) -> R<Condition> {
+    if prefix_width == 0 || prefix_width > 8 {
+        return Err(err("Invalid frame length prefix width"));
+    }
+    if buf.len() < prefix_width {
+        return Err(err("Frame too short for length prefix"));
+    }
+    let (prefix, data) = buf.split_at(prefix_width);
+    let expected = prefix.iter().fold(0u64, |len, b| (len << 8) | *b as u64);
+    if expected != data.len() as u64 {
+        return Err(ConditionDecodeError::FrameLengthMismatch { expected, actual: data.len() });
+    }
+    decode_fulfillment(data, flags)
+}
+
+/// decode a fulfillment prefixed with a version byte and return the version with the fulfillment.
+/// Version 1 is the current encoding
+pub fn decode_versioned(buf: &[u8], flags: u32) -> R<(u8, Condition)> {
+    match buf.split_first() {
+        Some((&1, data)) => Ok((1, decode_fulfillment(data, flags)?)),
+        Some((&version, _)) => Err(ConditionDecodeError::UnsupportedVersion(version)),
+        None => Err(err("No version byte")),
+    }
+}
+
+/// decode a fulfillment and return the non canonical encodings found in it
+pub fn audit_fulfillment(buf: &[u8], flags: u32) -> R<Vec<CanonicalIssue>> {
+    let mut ctx = DecodeCtx::new(flags, &DecodeLimits::default());
+    let _ = decode_fulfillment_ctx(buf, &mut ctx)?;
+    Ok(ctx.issues.into_iter().map(|(_, issue)| issue).collect())
+}
+
+/// decode a fulfillment and return all its deviations from the canonical encoding (see CanonicalIssue) with
+/// their paths. This is audit_fulfillment with threshold order checked as well
+pub fn lint_canonical(buf: &[u8], flags: u32) -> R<Vec<CanonicalLint>> {
+    let mut ctx = DecodeCtx::new(flags, &DecodeLimits::default());
+    ctx.lint = true;
+    let _ = decode_fulfillment_ctx(buf, &mut ctx)?;
+    Ok(ctx.issues.into_iter().map(|(path, issue)| CanonicalLint { path, issue }).collect())
+}
+
+/// decode a condition and return the non canonical encodings found in it
+pub fn audit_condition(buf: &[u8]) -> R<Vec<CanonicalIssue>> {
+    let mut ctx = DecodeCtx::new(0, &DecodeLimits::default());
+    let _ = decode_condition_ctx(buf, &mut ctx)?;
+    Ok(ctx.issues.into_iter().map(|(_, issue)| issue).collect())
+}
+
+fn decode_fulfillment_ctx(buf: &[u8], ctx: &mut DecodeCtx) -> R<Condition> {
+    let mut p = Parser::from_top_buf(buf, ctx)?;
+    let o = parse_fulfillment(&mut p, ctx)?;
+    let () = p.end()?;
+    let () = ctx.check_cost(&o)?;
+    Ok(o)
+}
+
+fn decode_condition_ctx(buf: &[u8], ctx: &mut DecodeCtx) -> R<Condition> {
+    let o = parse_condition(&mut Parser::from_top_buf(buf, ctx)?, ctx)?;
+    let () = ctx.check_cost(&o)?;
+    Ok(o)
 }
 
 // get condition type enum from cond_type value
@@ -33,71 +325,60 @@ pub fn condition_type_from_id(id: u8) -> Result<ConditionType, ConditionDecodeEr
         6 => Secp256k1HashType,
         15 => EvalType,
         0xff => AnonType,
-        _ => Err(ConditionDecodeError(format!("Unknown condition type id: {:?}", id)))?
+        _ => Err(ConditionDecodeError::Malformed(format!("Unknown condition type id: {:?}", id)))?
     })
 }
 
-struct Parser(Vec<ASN1Block>);
+struct Parser(Vec<Element>);
 
 impl Parser {
-    fn new(asns: Vec<ASN1Block>) -> Self {
+    fn new(asns: Vec<Element>) -> Self {
         Parser(asns)
     }
     fn from_buf(data: &[u8]) -> R<Parser> {
         if data.is_empty() {
             Ok(Parser(Vec::new()))
         } else {
-            match from_der(data) {
-                Ok(asns) => Ok(Self::new(asns)),
-                Err(_) => {
-                    Err(err("Invalid ASN data1"))
-                }
-            }
+            Backend::from_der(data).map(Self::new)
         }
     }
-    fn container(&mut self, type_id: u8) -> R<Parser> {
-        let (tid, buf) = self.lpop()?;
-        if tid == type_id {
-            Self::from_buf(&buf)
-        } else {
-            Err(err("Unexpected identifier in ASN"))
+    // parser of a whole encoding. If SEQUENCE_WRAPPED is set a single enclosing SEQUENCE is removed,
+    // encodings without one are read as they are
+    fn from_top_buf(data: &[u8], ctx: &DecodeCtx) -> R<Parser> {
+        let parser = Self::from_buf(data)?;
+        if ctx.flags & SEQUENCE_WRAPPED == 0 {
+            return Ok(parser);
         }
-    }
-    fn many<F, T>(&mut self, f: F, flags: u32) -> R<Vec<T>>
-    where
-        F: Fn(&mut Parser, u32) -> R<T>,
-    {
-        let mut out = Vec::new();
-        while !self.0.is_empty() {
-            out.push(f(self, flags)?);
+        match parser.0.as_slice() {
+            [elem] => Ok(Backend::sequence(elem).map(Self::new).unwrap_or(parser)),
+            _ => Ok(parser),
         }
-        Ok(out)
     }
     fn lpop(&mut self) -> R<(u8, Vec<u8>)> {
         if self.0.is_empty() {
             return Err(err("Expected element"));
         }
-        let asn = self.0.remove(0);
-        match asn {
-            ASN1Block::Unknown(ASN1Class::ContextSpecific, _, _, type_id, buf) => {
-                Ok((type_id.to_u8().ok_or(err("Invalid type id"))?, buf))
+        Backend::context_specific(self.0.remove(0))
+    }
+    fn peek_type_id(&self) -> Option<u8> {
+        self.0.first().and_then(Backend::context_tag)
+    }
+    // pop the next element if it is a NULL
+    fn pop_null(&mut self) -> bool {
+        match self.0.first().map(Backend::is_null) {
+            Some(true) => {
+                let _ = self.0.remove(0);
+                true
             },
-            //ASN1Block::Explicit(ASN1Class::ContextSpecific, _, type_id, box_) => {
-            //    let unbox = *box_;
-            //    if let ASN1Block::Unknown(ASN1Class::ContextSpecific, false, _, _, buf) = unbox {
-            //        let buf_ = to_der(&internal::asn_unknown(false, 0, buf.to_vec())).unwrap();
-            //        // TODO: safe to_der for decoding
-            //        Ok((type_id.to_u8().ok_or(err("Invalid type id"))?, buf_))
-            //    } else if let ASN1Block::Explicit(ASN1Class::ContextSpecific, _, type_id_2, box_) = unbox {
-            //        unimplemented!("")
-            //        
-            //    } else {
-            //        println!("{:?}", unbox);
-            //        Err(err("unexpected structure1"))
-            //    }
-            //}
-            _ => Err(err("unexpected structure2")),
+            _ => false,
+        }
+    }
+    // pop the next element undecoded, as der
+    fn raw(&mut self) -> R<Vec<u8>> {
+        if self.0.is_empty() {
+            return Err(err("Expected element"));
         }
+        Backend::to_der(&self.0.remove(0))
     }
     fn any(&mut self) -> R<(u8, Parser)> {
         let (tid, buf) = self.lpop()?;
@@ -107,12 +388,15 @@ impl Parser {
         let (t, buf) = self.lpop()?;
         match t == type_id {
             true => Ok(buf),
-            _ => Err(ConditionDecodeError(format!(
+            _ => Err(ConditionDecodeError::Malformed(format!(
                 "Wrong type id, expected: {:?} but got: {:?}",
                 type_id, t
             ))),
         }
     }
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
     fn end(&self) -> R<()> {
         match self.0.is_empty() {
             true => Ok(()),
@@ -121,14 +405,15 @@ impl Parser {
     }
 }
 
-fn parse_fulfillment(parser: &mut Parser, flags: u32) -> R<Condition> {
+fn parse_fulfillment(parser: &mut Parser, ctx: &mut DecodeCtx) -> R<Condition> {
     let (tid, mut p) = parser.any()?;
     //let () = parser.end()?;
     let o = match tid {
-        0 => parse_preimage(&mut p),
-        2 => parse_threshold(&mut p, flags),
-        5 => parse_secp256k1(&mut p),
-        6 => parse_secp256k1hash(&mut p),
+        0 => parse_preimage(&mut p, ctx),
+        2 => parse_threshold(&mut p, parse_fulfillment, ctx)
+            .map(|(threshold, subconditions)| Threshold { threshold, subconditions }),
+        5 => parse_secp256k1(&mut p, ctx),
+        6 => parse_secp256k1hash(&mut p, ctx),
         15 => parse_eval(&mut p),
         _ => Err(err("Invalid Condition ASN")),
     }?;
@@ -136,16 +421,37 @@ fn parse_fulfillment(parser: &mut Parser, flags: u32) -> R<Condition> {
     Ok(o)
 }
 
-fn parse_condition(top_parser: &mut Parser, _flags: u32) -> R<Condition> {
+// parse_fulfillment down to ctx.max_levels of thresholds
+fn parse_fulfillment_shallow(parser: &mut Parser, ctx: &mut DecodeCtx) -> R<ShallowCondition> {
+    if parser.peek_type_id() != Some(ThresholdType.id()) {
+        return parse_fulfillment(parser, ctx).map(ShallowCondition::Decoded);
+    }
+    if matches!(ctx.max_levels, Some(max_levels) if ctx.depth >= max_levels) {
+        // the raw subtree has no wrapper
+        return Ok(ShallowCondition::Undecoded { fulfillment: parser.raw()?, flags: ctx.flags & !SEQUENCE_WRAPPED });
+    }
+    let (_, mut p) = parser.any()?;
+    ctx.depth += 1;
+    let (threshold, subconditions) = parse_threshold(&mut p, parse_fulfillment_shallow, ctx)?;
+    ctx.depth -= 1;
+    let () = p.end()?;
+    Ok(ShallowCondition::Threshold { threshold, subconditions })
+}
+
+fn parse_condition(top_parser: &mut Parser, ctx: &mut DecodeCtx) -> R<Condition> {
     let (type_id, mut parser) = top_parser.any()?;
     let cond_type = condition_type_from_id(type_id)?;
     let () = top_parser.end()?;
     let fingerprint = pad_fingerprint( &parser.buf(0)?, &cond_type);  // pad to 32 bytes
-    let cost = BigInt::from_signed_bytes_be(&parser.buf(1)?)
+    let cost_buf = parser.buf(1)?;
+    if !is_minimal_integer(&cost_buf) {
+        ctx.issue(CanonicalIssue::NonMinimalCost(cost_buf.clone()));
+    }
+    let cost = BigInt::from_signed_bytes_be(&cost_buf)
         .to_u64()
         .ok_or(err("Can't decode cost"))?;
     let subtypes = match cond_type.has_subtypes() {
-        true => internal::unpack_set(parser.buf(2)?),
+        true => parse_subtypes(parser.buf(2)?, ctx)?,
         _ => HashSet::new(),
     };
     let () = parser.end()?;
@@ -157,18 +463,52 @@ fn parse_condition(top_parser: &mut Parser, _flags: u32) -> R<Condition> {
     })
 }
 
-fn parse_preimage(parser: &mut Parser) -> R<Condition> {
-    Ok(Preimage {
-        preimage: parser.buf(0)?,
-    })
+// subtypes is an implicitly tagged BIT STRING: the unused bits count byte followed by the bits
+fn parse_subtypes(buf: Vec<u8>, ctx: &mut DecodeCtx) -> R<HashSet<u8>> {
+    let valid = match buf.split_first() {
+        Some((&bits_unused, bits)) => bits_unused < 8 && (bits_unused == 0 || !bits.is_empty()),
+        None => false,
+    };
+    if !valid {
+        return Err(err("Invalid subtypes bit string"));
+    }
+    let subtypes = internal::unpack_set(buf.clone());
+    if !is_canonical_subtypes(&buf) {
+        ctx.issue(CanonicalIssue::NonCanonicalSubtypes(buf));
+    }
+    Ok(subtypes)
 }
 
-fn parse_secp256k1(parser: &mut Parser) -> R<Condition> {
-    match (
-        PublicKey::parse_slice(&parser.buf(0)?, None),
-        Signature::parse_standard_slice(&parser.buf(1)?),
-    ) {
-        (Ok(pubkey), Ok(sig)) => Ok(Secp256k1 {
+// whether buf is written as pack_set does: no trailing zero bytes and the unused bits count up to the last set bit
+// (all of them unused), or the empty set as [7, 0]
+fn is_canonical_subtypes(buf: &[u8]) -> bool {
+    match buf {
+        [7, 0] => true,
+        [bits_unused, .., last] => *last != 0 && last.trailing_zeros() == *bits_unused as u32,
+        _ => false,
+    }
+}
+
+fn parse_preimage(parser: &mut Parser, ctx: &mut DecodeCtx) -> R<Condition> {
+    let preimage = parser.buf(0)?;
+    let () = ctx.add_preimage(preimage.len())?;
+    Ok(Preimage { preimage })
+}
+
+// Decoded pubkeys are stored inline and repeated keys are not shared. The inline PublicKey makes Secp256k1Hash the
+// largest variant, which sets the size of every Condition node; sharing keys through an Rc would shrink the nodes
+// but needs the public pubkey fields to change type, so it is not a decode option
+fn parse_secp256k1(parser: &mut Parser, ctx: &mut DecodeCtx) -> R<Condition> {
+    let pubkey = parse_pubkey(&parser.buf(0)?, ctx).map_err(|_| err("Bad ASN1 secp256k1"))?;
+    // the strict default requires the signature
+    if parser.is_empty() && ctx.flags & ALLOW_NO_SIGNATURE != 0 {
+        return Ok(Secp256k1 { pubkey, signature: None });
+    }
+    if ctx.flags & NULL_SIGNATURE != 0 && parser.pop_null() {
+        return Ok(Secp256k1 { pubkey, signature: None });
+    }
+    match parse_signature(&parser.buf(1)?, ctx) {
+        Ok(sig) => Ok(Secp256k1 {
             pubkey,
             signature: Some(sig),
         }),
@@ -177,10 +517,15 @@ fn parse_secp256k1(parser: &mut Parser) -> R<Condition> {
 }
 
 // secp256k1hash fulfillment equals to the secp256k1 fulfillment (pubkey + signature)
-fn parse_secp256k1hash(parser: &mut Parser) -> R<Condition> {
+fn parse_secp256k1hash(parser: &mut Parser, ctx: &mut DecodeCtx) -> R<Condition> {
+    let pubkey_buf = parser.buf(0)?;
+    if ctx.flags & NULL_SIGNATURE != 0 && parser.pop_null() {
+        let pubkey = parse_pubkey(&pubkey_buf, ctx).map_err(|_| err("Bad ASN1 secp256k1hash"))?;
+        return Ok(Secp256k1Hash { pubkey_hash: None, pubkey: Some(pubkey), signature: None });
+    }
     match (
-        PublicKey::parse_slice(&parser.buf(0)?, None),
-        Signature::parse_standard_slice(&parser.buf(1)?),
+        parse_pubkey(&pubkey_buf, ctx),
+        parse_signature(&parser.buf(1)?, ctx),
     ) {
         (Ok(pk), Ok(sig)) => Ok(Secp256k1Hash {
 
@@ -192,27 +537,178 @@ fn parse_secp256k1hash(parser: &mut Parser) -> R<Condition> {
     }
 }
 
-fn parse_threshold(parser: &mut Parser, flags: u32) -> R<Condition> {
-    if flags & MIXED_MODE != 0 { return parse_threshold_mixed(parser, flags); }
-    let mut ffills = parser.container(0)?.many(parse_fulfillment, flags)?;
-    let mut conds = parser.container(1)?.many(parse_condition, flags)?;
+fn parse_pubkey(buf: &[u8], ctx: &mut DecodeCtx) -> Result<PublicKey, libsecp256k1::Error> {
+    let pubkey = PublicKey::parse_slice(buf, None)?;
+    if buf.len() == 65 {
+        ctx.issue(CanonicalIssue::UncompressedPubkey);
+    }
+    Ok(pubkey)
+}
+
+// half the secp256k1 curve order, the largest low S
+const HALF_ORDER: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+// the signature is r followed by s, 32 bytes each in big endian.
+// SWAPPED_SIGNATURE_BYTES reverses the bytes of each half (not the order of r and s) before parsing
+fn parse_signature(buf: &[u8], ctx: &mut DecodeCtx) -> Result<Signature, libsecp256k1::Error> {
+    let signature = match ctx.flags & SWAPPED_SIGNATURE_BYTES == 0 || buf.len() != 64 {
+        true => Signature::parse_standard_slice(buf)?,
+        _ => {
+            let mut swapped = buf.to_vec();
+            swapped[..32].reverse();
+            swapped[32..].reverse();
+            Signature::parse_standard_slice(&swapped)?
+        },
+    };
+    if signature.serialize()[32..] > HALF_ORDER[..] {
+        ctx.issue(CanonicalIssue::HighS);
+    }
+    Ok(signature)
+}
+
+// type id and the (tag, content length) of the children of a context specific element
+fn top_level(elem: Element) -> Option<(u8, Vec<(u8, usize)>)> {
+    let (tag, content) = Backend::context_specific(elem).ok()?;
+    let mut children = Parser::from_buf(&content).ok()?;
+    let mut out = Vec::new();
+    while !children.is_empty() {
+        let (child_tag, buf) = children.lpop().ok()?;
+        out.push((child_tag, buf.len()));
+    }
+    Some((tag, out))
+}
+
+// whether the top level of elem has the layout of a condition: fingerprint, cost and the subtypes of compound types
+fn is_condition_layout(elem: &Element) -> bool {
+    match top_level(elem.clone()) {
+        Some((tag, children)) => match condition_type_from_id(tag) {
+            Ok(cond_type) if cond_type.has_subtypes() => matches!(children.as_slice(), [(0, 32), (1, 1..=9), (2, _)]),
+            Ok(_) => matches!(children.as_slice(), [(0, 20), (1, 1..=9)] | [(0, 32), (1, 1..=9)]),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+// whether the top level of elem has the layout of a fulfillment of its type
+fn is_fulfillment_layout(elem: &Element) -> bool {
+    let pubkey = |len: &usize| *len == 33 || *len == 65;
+    match top_level(elem.clone()) {
+        Some((tag, children)) => match (tag, children.as_slice()) {
+            (0, [(0, _)]) | (15, [(0, _)]) | (2, [(0, _), (1, _)]) => true,
+            (5, [(0, len)]) | (6, [(0, len)]) | (5, [(0, len), (1, 64)]) | (6, [(0, len), (1, 64)]) => pubkey(len),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+// parse all elements of a threshold container (0 for fulfillments, 1 for conditions), errors get the path of
+// the container or element. An element that fails and has the top level layout of the other container only is
+// a ThresholdContainerMismatch
+fn parse_container<T, F>(parser: &mut Parser, type_id: u8, name: &str, f: F, ctx: &mut DecodeCtx) -> R<Vec<T>>
+where
+    T: Subcondition,
+    F: Fn(&mut Parser, &mut DecodeCtx) -> R<T>,
+{
+    ctx.path.push(name.to_string());
+    let content = match parser.lpop().map_err(|e| ctx.locate(e))? {
+        (tid, buf) if tid == type_id => buf,
+        _ => return Err(ctx.locate(err("Unexpected identifier in ASN"))),
+    };
+    let mut p = Parser::from_buf(&content).map_err(|e| ctx.locate(e))?;
+    // the mixed mode encoder sorts the elements by their encoding (see x690sort)
+    let unsorted_der = ctx.lint && ctx.flags & MIXED_MODE != 0 && {
+        let ders = p.0.iter().map(Backend::to_der).collect::<R<Vec<_>>>()?;
+        ders.windows(2).any(|pair| pair[0] > pair[1])
+    };
+    let _ = ctx.path.pop();
+    let mut out = Vec::new();
+    while !p.is_empty() {
+        ctx.path.push(format!("{}[{}]", name, out.len()));
+        match f(&mut p, ctx) {
+            Ok(cond) => out.push(cond),
+            Err(e) => {
+                // f took the element, read it again from the container
+                let elem = Parser::from_buf(&content)?.0.into_iter().nth(out.len());
+                let (fits, fits_other) = match (type_id, &elem) {
+                    (_, None) => (true, false),
+                    (0, Some(elem)) => (is_fulfillment_layout(elem), is_condition_layout(elem)),
+                    (_, Some(elem)) => (is_condition_layout(elem), is_fulfillment_layout(elem)),
+                };
+                return match e {
+                    ConditionDecodeError::Malformed(_) if fits_other && !fits => {
+                        Err(ConditionDecodeError::ThresholdContainerMismatch { path: ctx.location() })
+                    },
+                    e => Err(ctx.locate(e)),
+                };
+            },
+        }
+        let _ = ctx.path.pop();
+    }
+    // the default encoder writes the subfulfillments by cost, the subconditions in the order of the condition
+    let unsorted_cost = ctx.lint && ctx.flags & MIXED_MODE == 0 && type_id == 0 && {
+        let costs: Vec<u64> = out.iter().filter_map(|c| c.decoded().map(Condition::cost)).collect();
+        costs.windows(2).any(|pair| pair[0] > pair[1])
+    };
+    if unsorted_der || unsorted_cost {
+        ctx.path.push(name.to_string());
+        ctx.issue(CanonicalIssue::UnsortedThreshold);
+        let _ = ctx.path.pop();
+    }
+    Ok(out)
+}
+
+// subconditions of the threshold parsers, Condition or ShallowCondition
+trait Subcondition: From<Condition> {
+    fn decoded(&self) -> Option<&Condition>;
+}
+
+impl Subcondition for Condition {
+    fn decoded(&self) -> Option<&Condition> {
+        Some(self)
+    }
+}
+
+impl Subcondition for ShallowCondition {
+    fn decoded(&self) -> Option<&Condition> {
+        match self {
+            ShallowCondition::Decoded(cond) => Some(cond),
+            _ => None,
+        }
+    }
+}
+
+// threshold and subconditions of a threshold fulfillment, with the subfulfillments parsed by f
+fn parse_threshold<T, F>(parser: &mut Parser, f: F, ctx: &mut DecodeCtx) -> R<(u16, Vec<T>)>
+where
+    T: Subcondition,
+    F: Fn(&mut Parser, &mut DecodeCtx) -> R<T>,
+{
+    if ctx.flags & MIXED_MODE != 0 { return parse_threshold_mixed(parser, f, ctx); }
+    let mut ffills = parse_container(parser, 0, "subfulfillments", f, ctx)?;
+    let conds = parse_container(parser, 1, "subconditions", parse_condition, ctx)?;
     let () = parser.end()?;
     let t = ffills.len() as u16;
-    ffills.append(&mut conds);
-    Ok(Threshold {
-        threshold: t,
-        subconditions: ffills,
-    })
+    ffills.extend(conds.into_iter().map(T::from));
+    Ok((t, ffills))
 }
 
-fn parse_threshold_mixed(parser: &mut Parser, flags: u32) -> R<Condition> {
-    let mut ffills = parser.container(0)?.many(parse_fulfillment, flags)?;
-    let conds = parser.container(1)?.many(parse_condition, flags)?;
+fn parse_threshold_mixed<T, F>(parser: &mut Parser, f: F, ctx: &mut DecodeCtx) -> R<(u16, Vec<T>)>
+where
+    T: Subcondition,
+    F: Fn(&mut Parser, &mut DecodeCtx) -> R<T>,
+{
+    let mut ffills = parse_container(parser, 0, "subfulfillments", f, ctx)?;
+    let conds = parse_container(parser, 1, "subconditions", parse_condition, ctx)?;
     let () = parser.end()?;
     if ffills.len() == 0 { return Err(err("no fulfillments")); }
     let t;
-    match &ffills[0] {
-        Preimage{ preimage } => {
+    match ffills[0].decoded() {
+        Some(Preimage{ preimage }) if !preimage.is_empty() => {
             t = preimage[0];
         }
         _ => { return Err(err("incorrect mixed mode threshold condition")); }
@@ -222,13 +718,10 @@ fn parse_threshold_mixed(parser: &mut Parser, flags: u32) -> R<Condition> {
 
     ffills.remove(0);
     for i in 0..conds.len() {
-        ffills.push(conds[i].to_anon());
+        ffills.push(T::from(conds[i].to_anon()));
     } 
 
-    Ok(Threshold {
-        threshold: t as u16,
-        subconditions: ffills,
-    })
+    Ok((t as u16, ffills))
 }
 
 fn parse_eval(parser: &mut Parser) -> R<Condition> {
@@ -237,8 +730,18 @@ fn parse_eval(parser: &mut Parser) -> R<Condition> {
     Ok(Eval { code })
 }
 
+// a DER integer has at least one byte and no leading 0x00 or 0xff byte which only repeats the sign bit of the next byte
+fn is_minimal_integer(buf: &[u8]) -> bool {
+    match buf {
+        [] => false,
+        [0x00, next, ..] => next & 0x80 != 0,
+        [0xff, next, ..] => next & 0x80 == 0,
+        _ => true,
+    }
+}
+
 fn err(s: &str) -> ConditionDecodeError {
-    ConditionDecodeError(s.into())
+    ConditionDecodeError::Malformed(s.into())
 }
 
 pub fn pad_fingerprint(v : &Vec<u8>,  cond_type : &ConditionType ) -> Vec<u8> {
@@ -263,4 +766,349 @@ pub fn shrink_fingerprint(v : &Vec<u8>,  cond_type : &ConditionType ) -> Vec<u8>
         _ => v[0..32].to_vec()
     };
     fingerprint_truncated
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsecp256k1::SecretKey;
+
+    fn from_der(data: &[u8]) -> R<Vec<Element>> {
+        Backend::from_der(data)
+    }
+
+    #[test]
+    fn test_decode_cumulative_preimage_limit() {
+        let cond = Threshold {
+            threshold: 10,
+            subconditions: (0..10).map(|i| Preimage { preimage: vec![i; 100] }).collect()
+        };
+        let ffil = cond.encode_fulfillment(0).unwrap();
+        let limits = DecodeLimits {
+            max_preimage_len: Some(200),
+            max_total_preimage_len: Some(500),
+            min_cost: None,
+        };
+
+        assert!(decode_fulfillment(&ffil, 0).is_ok());
+        assert_eq!(
+            decode_fulfillment_with_limits(&ffil, 0, &limits),
+            Err(ConditionDecodeError::CumulativePreimageTooLarge(600)));
+        assert_eq!(
+            decode_fulfillment_with_limits(&ffil, 0, &DecodeLimits { max_preimage_len: Some(50), ..limits.clone() }),
+            Err(ConditionDecodeError::PreimageTooLarge(100)));
+        assert!(decode_fulfillment_with_limits(&ffil, 0, &DecodeLimits { max_total_preimage_len: Some(1000), ..limits }).is_ok());
+    }
+
+    #[test]
+    fn test_decode_secp256k1_without_signature() {
+        let pubkey = PublicKey::from_secret_key(&SecretKey::parse(&[1; 32]).unwrap());
+        let ffil = internal::encode_asn(&internal::asn_choice(
+            Secp256k1Type.id(),
+            &internal::asn_data(&vec![pubkey.serialize_compressed().to_vec()])));
+
+        assert_eq!(decode_fulfillment(&ffil, 0), Err(ConditionDecodeError::Malformed("Expected element".into())));
+        assert_eq!(decode_fulfillment(&ffil, ALLOW_NO_SIGNATURE), Ok(Secp256k1 { pubkey, signature: None }));
+    }
+
+    #[test]
+    fn test_audit_non_minimal_cost() {
+        let fingerprint = internal::sha256(vec![1]);
+        let canonical = Preimage { preimage: vec![1] }.encode_condition();
+        let non_minimal = internal::encode_asn(&internal::asn_choice(
+            PreimageType.id(),
+            &internal::asn_data(&vec![fingerprint, vec![0x00, 0x01]])));
+
+        assert_eq!(audit_condition(&canonical), Ok(vec![]));
+        assert_eq!(audit_condition(&non_minimal), Ok(vec![CanonicalIssue::NonMinimalCost(vec![0x00, 0x01])]));
+        // decodes to the same condition but does not re-encode to the same bytes
+        let decoded = decode_condition(&non_minimal).unwrap();
+        assert_eq!(decoded, decode_condition(&canonical).unwrap());
+        assert_ne!(decoded.encode_condition(), non_minimal);
+
+        assert!(is_minimal_integer(&[0x00, 0x80]));
+        assert!(!is_minimal_integer(&[0xff, 0x7f]));
+        assert!(!is_minimal_integer(&[]));
+    }
+
+    #[test]
+    fn test_decode_subtypes_bit_string() {
+        let condition = |subtypes: Vec<u8>| internal::encode_asn(&internal::asn_choice(
+            ThresholdType.id(),
+            &internal::asn_data(&vec![vec![0; 32], vec![0x01], subtypes])));
+        let preimage_and_secp256k1: HashSet<u8> = vec![PreimageType.id(), Secp256k1Type.id()].into_iter().collect();
+        let subtypes = |cond: Condition| match cond {
+            Anon { subtypes, .. } => subtypes,
+            _ => panic!("not anon"),
+        };
+
+        // reference BIT STRING with bits 0 and 5 set and 2 unused bits
+        assert_eq!(subtypes(decode_condition(&condition(vec![0x02, 0x84])).unwrap()), preimage_and_secp256k1);
+        assert_eq!(internal::pack_set(preimage_and_secp256k1.clone()), vec![0x02, 0x84]);
+        // unused bits are ignored even if an encoder did not zero them
+        assert_eq!(subtypes(decode_condition(&condition(vec![0x02, 0x87])).unwrap()), preimage_and_secp256k1);
+        assert!(decode_condition(&condition(vec![0x08, 0x84])).is_err());
+        assert!(decode_condition(&condition(vec![0x01])).is_err());
+        assert!(decode_condition(&condition(vec![])).is_err());
+
+        // type ids past the 4 bytes of the known types decode and re-encode the same
+        for bits in vec![vec![0x07, 0, 0, 0, 0x80, 0x80], vec![0x07, 0, 0, 0, 0, 0x80]] {
+            let encoded = condition(bits);
+            assert_eq!(audit_condition(&encoded), Ok(vec![]));
+            assert_eq!(decode_condition(&encoded).unwrap().encode_condition(), encoded);
+        }
+        let high: HashSet<u8> = vec![24, 32].into_iter().collect();
+        assert_eq!(subtypes(decode_condition(&condition(vec![0x07, 0, 0, 0, 0x80, 0x80])).unwrap()), high);
+    }
+
+    #[test]
+    fn test_decode_fulfillment_framed() {
+        let cond = Preimage { preimage: vec![1; 200] };
+        let ffil = cond.encode_fulfillment(0).unwrap();
+        let framed = |len: u32, data: &[u8]| [&len.to_be_bytes()[..], data].concat();
+
+        assert_eq!(decode_fulfillment_framed(&framed(ffil.len() as u32, &ffil), 0), Ok(cond.clone()));
+        // length says more than present: truncated response
+        assert_eq!(
+            decode_fulfillment_framed(&framed(ffil.len() as u32 + 20, &ffil), 0),
+            Err(ConditionDecodeError::FrameLengthMismatch { expected: ffil.len() as u64 + 20, actual: ffil.len() }));
+        // length says less than present
+        assert_eq!(
+            decode_fulfillment_framed(&framed(ffil.len() as u32 - 20, &ffil), 0),
+            Err(ConditionDecodeError::FrameLengthMismatch { expected: ffil.len() as u64 - 20, actual: ffil.len() }));
+        assert!(decode_fulfillment_framed(&[0, 0], 0).is_err());
+
+        let framed_2 = [&(ffil.len() as u16).to_be_bytes()[..], &ffil[..]].concat();
+        assert_eq!(decode_fulfillment_framed_width(&framed_2, 0, 2), Ok(cond));
+        assert!(decode_fulfillment_framed_width(&framed_2, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_decode_versioned() {
+        let cond = Preimage { preimage: vec![1, 2, 3] };
+        let ffil = cond.encode_fulfillment(0).unwrap();
+        let v1 = [&[1u8][..], &ffil[..]].concat();
+        let v2 = [&[2u8][..], &ffil[..]].concat();
+
+        assert_eq!(decode_versioned(&v1, 0), Ok((1, cond)));
+        assert_eq!(decode_versioned(&v2, 0), Err(ConditionDecodeError::UnsupportedVersion(2)));
+        assert!(decode_versioned(&[], 0).is_err());
+    }
+
+    #[test]
+    fn test_decode_fulfillment_shallow() {
+        let level3 = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: b"deep".to_vec() }] };
+        let level2 = Threshold { threshold: 1, subconditions: vec![level3] };
+        let root = Threshold { threshold: 2, subconditions: vec![level2, Preimage { preimage: b"top".to_vec() }] };
+        let ffil = root.encode_fulfillment(0).unwrap();
+        let full = decode_fulfillment(&ffil, 0).unwrap();
+
+        let shallow = decode_fulfillment_shallow(&ffil, 0, 1).unwrap();
+        let subconditions = match &shallow {
+            ShallowCondition::Threshold { subconditions, .. } => subconditions,
+            _ => panic!("root is not a threshold"),
+        };
+        let undecoded: Vec<&ShallowCondition> =
+            subconditions.iter().filter(|c| matches!(c, ShallowCondition::Undecoded { .. })).collect();
+        assert_eq!(undecoded.len(), 1);
+        assert!(subconditions.contains(&ShallowCondition::Decoded(Preimage { preimage: b"top".to_vec() })));
+
+        // the undecoded subtree is the level 2 threshold with its level 3 child
+        match undecoded[0].expand().unwrap() {
+            Threshold { subconditions, .. } => assert!(matches!(subconditions[0], Threshold { .. })),
+            _ => panic!("undecoded subtree is not a threshold"),
+        }
+        assert_eq!(shallow.expand(), Ok(full.clone()));
+        assert_eq!(decode_fulfillment_shallow(&ffil, 0, 3).unwrap().expand(), Ok(full));
+
+        // a bad subtree is an error of expand(), not a panic
+        let bad = ShallowCondition::Undecoded { fulfillment: vec![0xa2, 0x00], flags: 0 };
+        assert!(bad.expand().is_err());
+        assert!(matches!(decode_fulfillment_shallow(&ffil, 0, 0), Ok(ShallowCondition::Undecoded { .. })));
+    }
+
+    #[test]
+    fn test_decode_sequence_wrapped() {
+        // the tool wraps with its ASN.1 encoder: SEQUENCE { element }, written here with the asn backend the same way
+        let wrap = |der: &[u8]| internal::encode_asn(&internal::asn_sequence(from_der(der).unwrap()));
+        let cond = Preimage { preimage: vec![1, 2, 3] };
+        let wrapped = wrap(&cond.encode_condition());
+        assert_eq!(hex::encode(&wrapped),
+            "3027a0258020039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81810103");
+
+        assert_eq!(decode_condition_with_flags(&wrapped, SEQUENCE_WRAPPED), Ok(cond.to_anon()));
+        assert!(decode_condition(&wrapped).is_err());
+        // the flag still accepts unwrapped encodings
+        assert_eq!(decode_condition_with_flags(&cond.encode_condition(), SEQUENCE_WRAPPED), Ok(cond.to_anon()));
+
+        let ffil = cond.encode_fulfillment(0).unwrap();
+        assert_eq!(decode_fulfillment(&wrap(&ffil), SEQUENCE_WRAPPED), Ok(cond.clone()));
+        assert_eq!(decode_fulfillment(&ffil, SEQUENCE_WRAPPED), Ok(cond));
+
+        // a wrapper with a long form length
+        let big = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![7; 200] }] };
+        let wrapped_big = wrap(&big.encode_fulfillment(0).unwrap());
+        assert_eq!(wrapped_big[..2], [0x30, 0x81]);
+        assert_eq!(decode_fulfillment(&wrapped_big, SEQUENCE_WRAPPED), Ok(big));
+    }
+
+    #[test]
+    fn test_decode_min_cost() {
+        let limits = DecodeLimits { min_cost: Some(1000), ..DecodeLimits::default() };
+        let tiny = Preimage { preimage: vec![1] };
+        let secp256k1 = Secp256k1 { pubkey: PublicKey::from_secret_key(&SecretKey::parse(&[1; 32]).unwrap()), signature: None };
+
+        assert_eq!(
+            decode_condition_with_limits(&tiny.encode_condition(), 0, &limits),
+            Err(ConditionDecodeError::CostTooLow(1)));
+        assert_eq!(
+            decode_fulfillment_with_limits(&tiny.encode_fulfillment(0).unwrap(), 0, &limits),
+            Err(ConditionDecodeError::CostTooLow(1)));
+        assert_eq!(decode_condition_with_limits(&secp256k1.encode_condition(), 0, &limits), Ok(secp256k1.to_anon()));
+        assert_eq!(decode_fulfillment(&tiny.encode_fulfillment(0).unwrap(), 0), Ok(tiny));
+    }
+
+    #[test]
+    fn test_decode_swapped_signature_bytes() {
+        // secp256k1 fulfillment from the legacy encoder, signature of sha256("legacy tx") with r and s byte reversed
+        let ffil = hex::decode(
+            "a5658021031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f8140\
+             49aad1129336a015086c901a7acadd56b0da50cad358c8ade561c9ea8932f517\
+             7d6bcf5b521490f4da706ffa298aaca951a88201d6d819c1f78523f603146324").unwrap();
+
+        let strict = decode_fulfillment(&ffil, 0).unwrap();
+        assert!(matches!(crate::verify(&strict, b"legacy tx"), Err(VerifyError::InvalidSignature(_))));
+
+        let swapped = decode_fulfillment(&ffil, SWAPPED_SIGNATURE_BYTES).unwrap();
+        assert_eq!(crate::verify(&swapped, b"legacy tx"), Ok(()));
+        // re-encodes in the standard order
+        assert_eq!(decode_fulfillment(&swapped.encode_fulfillment(0).unwrap(), 0), Ok(swapped));
+    }
+
+    #[test]
+    fn test_decode_null_signature() {
+        // unsigned secp256k1 template with a NULL as the signature
+        let ffil = hex::decode(
+            "a5258021031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f0500").unwrap();
+        let pubkey = PublicKey::from_secret_key(&SecretKey::parse(&[1; 32]).unwrap());
+
+        assert_eq!(decode_fulfillment(&ffil, NULL_SIGNATURE), Ok(Secp256k1 { pubkey, signature: None }));
+        assert!(decode_fulfillment(&ffil, 0).is_err());
+        assert!(decode_fulfillment(&ffil, ALLOW_NO_SIGNATURE).is_err());
+
+        // the same template as a secp256k1hash leaf
+        let ffil = hex::decode(
+            "a6258021031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f0500").unwrap();
+        let unsigned = Secp256k1Hash { pubkey_hash: None, pubkey: Some(pubkey), signature: None };
+        assert_eq!(decode_fulfillment(&ffil, NULL_SIGNATURE), Ok(unsigned.clone()));
+        // the condition of the template is the one of the pubkey hash
+        let by_hash = Secp256k1Hash { pubkey_hash: Some(internal::ripemd_sha(&pubkey)), pubkey: None, signature: None };
+        assert_eq!(unsigned.encode_condition(), by_hash.encode_condition());
+        assert!(decode_fulfillment(&ffil, 0).is_err());
+    }
+
+    #[test]
+    fn test_lint_canonical() {
+        use internal::{asn_choice, asn_data, encode_asn};
+        let uncompressed = hex::decode(
+            "041b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f\
+             70beaf8f588b541507fed6a642c5ab42dfdf8120a7f639de5122d47a69a8e8d1").unwrap();
+        let high_s = [&[0x01; 32][..], &[0xee; 32][..]].concat();
+        let ffils = vec![
+            // the secp256k1 leaf costs more than the preimage after it
+            asn_choice(Secp256k1Type.id(), &asn_data(&vec![uncompressed, high_s])),
+            asn_choice(PreimageType.id(), &asn_data(&vec![vec![1]])),
+        ];
+        let conds = vec![
+            asn_choice(PreimageType.id(), &asn_data(&vec![internal::sha256(vec![3]), vec![0x00, 0x01]])),
+            // subtypes {preimage}, canonical with 7 unused bits
+            asn_choice(ThresholdType.id(), &asn_data(&vec![vec![4; 32], vec![0x01], vec![0x00, 0x80]])),
+        ];
+        let ffil = encode_asn(&asn_choice(ThresholdType.id(), &vec![asn_choice(0, &ffils), asn_choice(1, &conds)]));
+        let lint = |path: &str, issue| CanonicalLint { path: path.into(), issue };
+
+        let lints = lint_canonical(&ffil, 0).unwrap();
+        assert_eq!(lints, vec![
+            lint("threshold.subfulfillments[0]", CanonicalIssue::UncompressedPubkey),
+            lint("threshold.subfulfillments[0]", CanonicalIssue::HighS),
+            lint("threshold.subfulfillments", CanonicalIssue::UnsortedThreshold),
+            lint("threshold.subconditions[0]", CanonicalIssue::NonMinimalCost(vec![0x00, 0x01])),
+            lint("threshold.subconditions[1]", CanonicalIssue::NonCanonicalSubtypes(vec![0x00, 0x80])),
+        ]);
+        assert_eq!(lints[0].to_string(), "Uncompressed pubkey at threshold.subfulfillments[0]");
+        assert_eq!(audit_fulfillment(&ffil, 0).unwrap().len(), 4);
+
+        let canonical = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![1] }, Eval { code: vec![1] }] };
+        assert_eq!(lint_canonical(&canonical.encode_fulfillment(0).unwrap(), 0), Ok(vec![]));
+
+        // same cost signatures in input order, 031b84.. before 024d4b.., which is how the encoder writes them
+        let secret = |i| SecretKey::parse(&[i; 32]).unwrap();
+        let mut multisig = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: PublicKey::from_secret_key(&secret(1)), signature: None },
+                Secp256k1 { pubkey: PublicKey::from_secret_key(&secret(2)), signature: None },
+            ]
+        };
+        assert_eq!(multisig.sign_multi(&[secret(1), secret(2)], b"msg"), 2);
+        let ffil = multisig.encode_fulfillment(0).unwrap();
+        assert_eq!(lint_canonical(&ffil, 0), Ok(vec![]));
+        let reencoded = decode_fulfillment(&ffil, 0).unwrap().encode_fulfillment(0).unwrap();
+        assert_eq!(reencoded, ffil);
+        // the mixed mode encoder sorts them by DER
+        assert_eq!(lint_canonical(&multisig.encode_fulfillment(MIXED_MODE).unwrap(), MIXED_MODE), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_decode_error_path() {
+        use internal::{asn_choice, asn_data, encode_asn};
+        // eval fulfillment without its code, in the threshold at subfulfillments[1]
+        let bad_eval = asn_choice(EvalType.id(), &vec![]);
+        let inner = asn_choice(ThresholdType.id(), &vec![asn_choice(0, &vec![bad_eval]), asn_choice(1, &vec![])]);
+        let preimage = asn_choice(PreimageType.id(), &asn_data(&vec![vec![1]]));
+        let root = asn_choice(ThresholdType.id(), &vec![asn_choice(0, &vec![preimage, inner]), asn_choice(1, &vec![])]);
+
+        let e = decode_fulfillment(&encode_asn(&root), 0).unwrap_err();
+        assert_eq!(e, ConditionDecodeError::MalformedAt {
+            path: "threshold.subfulfillments[1].subfulfillments[0]".into(),
+            message: "Expected element".into(),
+        });
+        assert_eq!(e.to_string(), "Expected element at threshold.subfulfillments[1].subfulfillments[0]");
+
+        // no subconditions container
+        let no_conds = asn_choice(ThresholdType.id(), &vec![asn_choice(0, &vec![])]);
+        assert!(matches!(
+            decode_fulfillment(&encode_asn(&no_conds), 0),
+            Err(ConditionDecodeError::MalformedAt { ref path, .. }) if path == "threshold.subconditions"));
+    }
+
+    #[test]
+    fn test_decode_threshold_container_mismatch() {
+        use internal::{asn_choice, encode_asn};
+        let ffil_asn = |cond: &Condition| from_der(&cond.encode_fulfillment(0).unwrap()).unwrap().remove(0);
+        let cond_asn = |cond: &Condition| from_der(&cond.encode_condition()).unwrap().remove(0);
+        let threshold = |ffils: Vec<Element>, conds: Vec<Element>| encode_asn(&asn_choice(ThresholdType.id(), &vec![asn_choice(0, &ffils), asn_choice(1, &conds)]));
+        let (a, b) = (Preimage { preimage: vec![1] }, Preimage { preimage: vec![2] });
+
+        assert!(decode_fulfillment(&threshold(vec![ffil_asn(&a)], vec![cond_asn(&b)]), 0).is_ok());
+        // swapped containers
+        assert_eq!(
+            decode_fulfillment(&threshold(vec![cond_asn(&b)], vec![ffil_asn(&a)]), 0),
+            Err(ConditionDecodeError::ThresholdContainerMismatch { path: "threshold.subfulfillments[0]".into() }));
+        assert_eq!(
+            decode_fulfillment(&threshold(vec![ffil_asn(&a)], vec![ffil_asn(&b)]), 0),
+            Err(ConditionDecodeError::ThresholdContainerMismatch { path: "threshold.subconditions[0]".into() }));
+        // errors of elements in the right container are kept, with their path
+        let limits = DecodeLimits { max_preimage_len: Some(0), ..DecodeLimits::default() };
+        assert_eq!(
+            decode_fulfillment_with_limits(&threshold(vec![ffil_asn(&a)], vec![cond_asn(&b)]), 0, &limits),
+            Err(ConditionDecodeError::PreimageTooLarge(1)));
+        let bad_secp256k1 = asn_choice(Secp256k1Type.id(), &internal::asn_data(&vec![vec![0; 33], vec![0; 64]]));
+        assert_eq!(
+            decode_fulfillment(&threshold(vec![bad_secp256k1], vec![cond_asn(&b)]), 0),
+            Err(ConditionDecodeError::MalformedAt {
+                path: "threshold.subfulfillments[0]".into(),
+                message: "Bad ASN1 secp256k1".into(),
+            }));
+    }
+}
@@ -0,0 +1,276 @@
+use std::convert::TryFrom;
+use libsecp256k1::{PublicKey, Signature};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+use crate::*;
+
+// json conditions and fulfillments in the same shape as the js api (see condition_js.rs):
+// binary fields are hex (publicKey, publicKeyHash, signature, codehex) or base64 (preimage, prefix, code, fingerprint)
+
+type R<T> = Result<T, ConditionDecodeError>;
+
+fn err(s: &str) -> ConditionDecodeError {
+    ConditionDecodeError::Malformed(s.into())
+}
+
+fn hex_field(js: &Value, key: &str) -> R<Option<Vec<u8>>> {
+    match js.get(key).and_then(Value::as_str) {
+        Some(s) => hex::decode(s).map(Some).map_err(|e| ConditionDecodeError::Malformed(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn base64_field(js: &Value, key: &str) -> R<Option<Vec<u8>>> {
+    match js.get(key).and_then(Value::as_str) {
+        Some(s) => base64::decode(s).map(Some).map_err(|e| ConditionDecodeError::Malformed(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn u64_field(js: &Value, key: &str) -> R<Option<u64>> {
+    match js.get(key) {
+        Some(v) => v.as_u64().map(Some).ok_or(ConditionDecodeError::Malformed(format!("'{}' is not a number", key))),
+        None => Ok(None),
+    }
+}
+
+fn pubkey_field(js: &Value) -> R<Option<PublicKey>> {
+    match hex_field(js, "publicKey")? {
+        Some(pk) => PublicKey::parse_slice(&pk, None).map(Some).map_err(|e| ConditionDecodeError::Malformed(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn signature_field(js: &Value) -> R<Option<Signature>> {
+    match hex_field(js, "signature")? {
+        Some(sig) => Signature::parse_standard_slice(&sig).map(Some).map_err(|e| ConditionDecodeError::Malformed(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// parse condition from json
+pub fn from_json(js: &Value) -> R<Condition> {
+    let cond_type = js.get("type").and_then(Value::as_str).ok_or(err("no 'type' property"))?;
+    match cond_type {
+        "threshold-sha-256" => {
+            let threshold = u64_field(js, "threshold")?.ok_or(err("no 'threshold' property"))?;
+            let subfulfillments = js.get("subfulfillments")
+                .and_then(Value::as_array)
+                .ok_or(err("no 'subfulfillments' array"))?;
+            Ok(Threshold {
+                threshold: u16::try_from(threshold).map_err(|_| err("threshold too big"))?,
+                subconditions: subfulfillments.iter().map(from_json).collect::<R<Vec<_>>>()?,
+            })
+        },
+        "eval-sha-256" => {
+            let code = match (base64_field(js, "code")?, hex_field(js, "codehex")?) {
+                (Some(code), None) | (None, Some(code)) => code,
+                (Some(_), Some(_)) => return Err(err("both 'code' and 'codehex' present")),
+                (None, None) => return Err(err("no 'code' eval property")),
+            };
+            Ok(Eval { code })
+        },
+        "secp256k1-sha-256" => Ok(Secp256k1 {
+            pubkey: pubkey_field(js)?.ok_or(err("no 'publicKey' property"))?,
+            signature: signature_field(js)?,
+        }),
+        "secp256k1hash-sha-256" => {
+            // decoded fulfillments have only the publicKey
+            let (pubkey_hash, pubkey) = (hex_field(js, "publicKeyHash")?, pubkey_field(js)?);
+            if pubkey_hash.is_none() && pubkey.is_none() {
+                return Err(err("no 'publicKeyHash' or 'publicKey' property"));
+            }
+            Ok(Secp256k1Hash { pubkey_hash, pubkey, signature: signature_field(js)? })
+        },
+        "preimage-sha-256" => Ok(Preimage {
+            preimage: base64_field(js, "preimage")?.ok_or(err("no 'preimage' property"))?,
+        }),
+        "prefix-sha-256" => Ok(Prefix {
+            prefix: base64_field(js, "prefix")?.ok_or(err("no 'prefix' property"))?,
+            max_message_len: u64_field(js, "maxMessageLength")?.ok_or(err("no 'maxMessageLength' property"))?,
+            subcondition: Box::new(from_json(js.get("subfulfillment").ok_or(err("no 'subfulfillment' property"))?)?),
+        }),
+        "(anon)" => {
+            let type_id = u64_field(js, "cond_type")?.ok_or(err("no 'cond_type' property"))?;
+            let mask = u64_field(js, "subtypes")?.unwrap_or(0);
+            if mask > u32::MAX as u64 {
+                return Err(err("too big type value in subtypes"));
+            }
+            Ok(Anon {
+                cond_type: condition_type_from_id(u8::try_from(type_id).map_err(|_| err("unknown cond_type"))?)?,
+                fingerprint: base64_field(js, "fingerprint")?.ok_or(err("no 'fingerprint' property"))?,
+                cost: u64_field(js, "cost")?.ok_or(err("no 'cost' property"))?,
+                // subtypes as u32 mask of type id bits
+                subtypes: (0..32u8).filter(|bit| mask & (1u64 << *bit) != 0).collect::<HashSet<u8>>(),
+            })
+        },
+        _ => Err(err("unknown condition type")),
+    }
+}
+
+/// make json from condition
+pub fn to_json(cond: &Condition) -> Value {
+    match cond {
+        Threshold { threshold, subconditions } => json!({
+            "type": ThresholdType.name(),
+            "threshold": threshold,
+            "subfulfillments": subconditions.iter().map(to_json).collect::<Vec<_>>(),
+        }),
+        Eval { code } => json!({ "type": EvalType.name(), "codehex": hex::encode(code) }),
+        Secp256k1 { pubkey, signature } => {
+            let mut js = json!({ "type": Secp256k1Type.name(), "publicKey": hex::encode(&pubkey.serialize_compressed()[..]) });
+            if let Some(signature) = signature {
+                js["signature"] = json!(hex::encode(&signature.serialize()[..]));
+            }
+            js
+        },
+        Secp256k1Hash { pubkey_hash, pubkey, signature } => {
+            let mut js = json!({ "type": Secp256k1HashType.name() });
+            if let Some(pubkey_hash) = pubkey_hash {
+                js["publicKeyHash"] = json!(hex::encode(pubkey_hash));
+            }
+            if let Some(pubkey) = pubkey {
+                js["publicKey"] = json!(hex::encode(&pubkey.serialize_compressed()[..]));
+            }
+            if let Some(signature) = signature {
+                js["signature"] = json!(hex::encode(&signature.serialize()[..]));
+            }
+            js
+        },
+        Preimage { preimage } => json!({ "type": PreimageType.name(), "preimage": base64::encode(preimage) }),
+        Prefix { prefix, max_message_len, subcondition } => json!({
+            "type": PrefixType.name(),
+            "prefix": base64::encode(prefix),
+            "maxMessageLength": max_message_len,
+            "subfulfillment": to_json(subcondition),
+        }),
+        Anon { cond_type, fingerprint, cost, subtypes } => {
+            let mut js = json!({
+                "type": AnonType.name(),
+                "cond_type": cond_type.id(),
+                "fingerprint": base64::encode(fingerprint),
+                "cost": cost,
+            });
+            if cond_type.has_subtypes() {
+                js["subtypes"] = json!(subtypes.iter().fold(0u32, |mask, bit| mask | 1u32.checked_shl(*bit as u32).unwrap_or(0)));
+            }
+            js
+        },
+    }
+}
+
+/// condition_uri and to_json of cond from one walk of the tree: the uri is computed from the anon forms
+/// of the subconditions built on the way, so the fingerprints below are not hashed again
+pub fn describe(cond: &Condition) -> (String, Value) {
+    let (anon, js) = describe_node(cond);
+    (condition_uri(&anon), js)
+}
+
+// anon form and json of cond
+fn describe_node(cond: &Condition) -> (Condition, Value) {
+    match cond {
+        Threshold { threshold, subconditions } => {
+            let (anons, subfulfillments): (Vec<Condition>, Vec<Value>) = subconditions.iter().map(describe_node).unzip();
+            let js = json!({
+                "type": ThresholdType.name(),
+                "threshold": threshold,
+                "subfulfillments": subfulfillments,
+            });
+            (Threshold { threshold: *threshold, subconditions: anons }.to_anon(), js)
+        },
+        Prefix { prefix, max_message_len, subcondition } => {
+            let (anon, subfulfillment) = describe_node(subcondition);
+            let js = json!({
+                "type": PrefixType.name(),
+                "prefix": base64::encode(prefix),
+                "maxMessageLength": max_message_len,
+                "subfulfillment": subfulfillment,
+            });
+            (Prefix { prefix: prefix.clone(), max_message_len: *max_message_len, subcondition: Box::new(anon) }.to_anon(), js)
+        },
+        _ => (cond.to_anon(), to_json(cond)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsecp256k1::SecretKey;
+
+    #[test]
+    fn test_json_round_trip() {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secret);
+        let mut signed = Secp256k1 { pubkey, signature: None };
+        signed.sign_secp256k1(&secret, &crate::verify::message_hash(b"msg")).unwrap();
+        let conds = vec![
+            Threshold {
+                threshold: 2,
+                subconditions: vec![
+                    signed,
+                    Preimage { preimage: vec![1, 2] },
+                    Eval { code: vec![0xe4] }.to_anon(),
+                    Threshold { threshold: 1, subconditions: vec![Eval { code: vec![1] }] }.to_anon()
+                ]
+            },
+            Secp256k1Hash { pubkey_hash: Some(internal::ripemd_sha(&pubkey)), pubkey: Some(pubkey), signature: None },
+            Prefix { prefix: vec![7], max_message_len: 32, subcondition: Box::new(Eval { code: vec![1] }) },
+        ];
+        for cond in conds {
+            assert_eq!(from_json(&to_json(&cond)), Ok(cond));
+        }
+    }
+
+    #[test]
+    fn test_json_decoded_secp256k1hash() {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secret);
+        let mut cond = Secp256k1Hash { pubkey_hash: Some(internal::ripemd_sha(&pubkey)), pubkey: None, signature: None };
+        cond.sign_secp256k1hash(&secret, &crate::verify::message_hash(b"msg")).unwrap();
+        let threshold = Threshold { threshold: 1, subconditions: vec![cond, Eval { code: vec![1] }] };
+
+        let decoded = decode_fulfillment(&threshold.encode_fulfillment(0).unwrap(), 0).unwrap();
+        let js = to_json(&decoded);
+        let secp256k1hash = js["subfulfillments"].as_array().unwrap().iter().find(|c| c["type"] == "secp256k1hash-sha-256");
+        assert_eq!(secp256k1hash.unwrap().get("publicKeyHash"), None);
+        assert_eq!(from_json(&js), Ok(decoded.clone()));
+        assert_eq!(from_json(&js).unwrap().fingerprint(), threshold.fingerprint());
+        assert!(from_json(&json!({ "type": "secp256k1hash-sha-256" })).is_err());
+    }
+
+    #[test]
+    fn test_describe() {
+        let preimage = Preimage { preimage: vec![1, 2, 3] };
+        assert_eq!(describe(&preimage), (
+            "ni:///sha-256;A5BYxvLAy0ksUzsKTRTvd8wPeKvMztUofYShogEc-4E?fpt=preimage-sha-256&cost=3".to_string(),
+            json!({ "type": "preimage-sha-256", "preimage": "AQID" })));
+
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                preimage,
+                Prefix { prefix: vec![7], max_message_len: 32, subcondition: Box::new(Eval { code: vec![1] }) },
+            ]
+        };
+        let (uri, js) = describe(&cond);
+        assert_eq!(uri, condition_uri(&cond));
+        assert!(uri.ends_with("&subtypes=preimage-sha-256,prefix-sha-256,eval-sha-256"));
+        assert_eq!(js, to_json(&cond));
+    }
+
+    #[test]
+    fn test_json_reference_anon() {
+        let js = json!({
+            "type": "(anon)",
+            "fingerprint": "ff66rHP4KxGz6nKw9hLKpQ7v1AfEni/MEIIoIMuR+R4=",
+            "cost": 132096,
+            "subtypes": 32,
+            "cond_type": 2
+        });
+        assert_eq!(
+            hex::encode(from_json(&js).unwrap().encode_condition()),
+            "a22b80207dfebaac73f82b11b3ea72b0f612caa50eefd407c49e2fcc10822820cb91f91e810302040082020204");
+        assert!(from_json(&json!({ "type": "eval-sha-256" })).is_err());
+    }
+}
@@ -1,7 +1,30 @@
 pub mod decoding;
 pub mod condition;
 pub mod condition_js;
+pub mod verify;
+pub mod tree;
+pub mod weighted;
+#[cfg(feature = "serde")]
+pub mod serialize;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "wif")]
+pub mod wif;
+#[cfg(feature = "bip32")]
+pub mod xpub;
+mod asn;
  
 pub use decoding::*;
 pub use condition::*;
 pub use condition_js::*;
+pub use verify::*;
+pub use tree::*;
+pub use weighted::*;
+#[cfg(feature = "serde")]
+pub use serialize::*;
+#[cfg(feature = "json")]
+pub use json::*;
+#[cfg(feature = "wif")]
+pub use wif::*;
+#[cfg(feature = "bip32")]
+pub use xpub::*;
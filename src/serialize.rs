@@ -0,0 +1,233 @@
+use std::convert::{TryFrom, TryInto};
+use libsecp256k1::{PublicKey, Signature};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+use serde_bytes::ByteBuf;
+
+use crate::*;
+
+// Serde model of Condition, for self describing formats other than the DER wire form.
+// Binary fields are serde bytes so formats with a byte string type (like cbor) store them as is,
+// keys and signatures are in their serialized secp256k1 form (33 byte compressed pubkey, 64 byte signature)
+#[derive(Serialize, Deserialize)]
+enum ConditionModel {
+    Threshold {
+        threshold: u16,
+        subconditions: Vec<ConditionModel>,
+    },
+    Preimage {
+        preimage: ByteBuf,
+    },
+    Prefix {
+        prefix: ByteBuf,
+        max_message_len: u64,
+        subcondition: Box<ConditionModel>,
+    },
+    Secp256k1 {
+        pubkey: ByteBuf,
+        signature: Option<ByteBuf>,
+    },
+    Secp256k1Hash {
+        pubkey_hash: Option<ByteBuf>,
+        pubkey: Option<ByteBuf>,
+        signature: Option<ByteBuf>,
+    },
+    Eval {
+        code: ByteBuf,
+    },
+    Anon {
+        cond_type: u8,
+        fingerprint: ByteBuf,
+        cost: u64,
+        subtypes: Vec<u8>,
+    },
+}
+
+fn pubkey_bytes(pubkey: &PublicKey) -> ByteBuf {
+    ByteBuf::from(pubkey.serialize_compressed().to_vec())
+}
+
+fn signature_bytes(signature: &Signature) -> ByteBuf {
+    ByteBuf::from(signature.serialize().to_vec())
+}
+
+fn parse_pubkey(buf: &ByteBuf) -> Result<PublicKey, String> {
+    PublicKey::parse_slice(buf, None).map_err(|e| e.to_string())
+}
+
+fn parse_signature(buf: &ByteBuf) -> Result<Signature, String> {
+    Signature::parse_standard_slice(buf).map_err(|e| e.to_string())
+}
+
+impl From<&Condition> for ConditionModel {
+    fn from(cond: &Condition) -> Self {
+        match cond {
+            Threshold { threshold, subconditions } => ConditionModel::Threshold {
+                threshold: *threshold,
+                subconditions: subconditions.iter().map(ConditionModel::from).collect(),
+            },
+            Preimage { preimage } => ConditionModel::Preimage { preimage: ByteBuf::from(preimage.to_vec()) },
+            Prefix { prefix, max_message_len, subcondition } => ConditionModel::Prefix {
+                prefix: ByteBuf::from(prefix.to_vec()),
+                max_message_len: *max_message_len,
+                subcondition: Box::new(ConditionModel::from(&**subcondition)),
+            },
+            Secp256k1 { pubkey, signature } => ConditionModel::Secp256k1 {
+                pubkey: pubkey_bytes(pubkey),
+                signature: signature.as_ref().map(signature_bytes),
+            },
+            Secp256k1Hash { pubkey_hash, pubkey, signature } => ConditionModel::Secp256k1Hash {
+                pubkey_hash: pubkey_hash.as_ref().map(|h| ByteBuf::from(h.to_vec())),
+                pubkey: pubkey.as_ref().map(pubkey_bytes),
+                signature: signature.as_ref().map(signature_bytes),
+            },
+            Eval { code } => ConditionModel::Eval { code: ByteBuf::from(code.to_vec()) },
+            Anon { cond_type, fingerprint, cost, subtypes } => {
+                let mut subtypes: Vec<u8> = subtypes.iter().cloned().collect();
+                subtypes.sort();  // stable output for the same condition
+                ConditionModel::Anon {
+                    cond_type: cond_type.id(),
+                    fingerprint: ByteBuf::from(fingerprint.to_vec()),
+                    cost: *cost,
+                    subtypes,
+                }
+            },
+        }
+    }
+}
+
+impl TryFrom<ConditionModel> for Condition {
+    type Error = String;
+
+    fn try_from(model: ConditionModel) -> Result<Self, Self::Error> {
+        Ok(match model {
+            ConditionModel::Threshold { threshold, subconditions } => Threshold {
+                threshold,
+                subconditions: subconditions
+                    .into_iter()
+                    .map(Condition::try_from)
+                    .collect::<Result<Vec<_>, _>>()?,
+            },
+            ConditionModel::Preimage { preimage } => Preimage { preimage: preimage.into_vec() },
+            ConditionModel::Prefix { prefix, max_message_len, subcondition } => Prefix {
+                prefix: prefix.into_vec(),
+                max_message_len,
+                subcondition: Box::new((*subcondition).try_into()?),
+            },
+            ConditionModel::Secp256k1 { pubkey, signature } => Secp256k1 {
+                pubkey: parse_pubkey(&pubkey)?,
+                signature: signature.as_ref().map(parse_signature).transpose()?,
+            },
+            ConditionModel::Secp256k1Hash { pubkey_hash, pubkey, signature } => Secp256k1Hash {
+                pubkey_hash: pubkey_hash.map(ByteBuf::into_vec),
+                pubkey: pubkey.as_ref().map(parse_pubkey).transpose()?,
+                signature: signature.as_ref().map(parse_signature).transpose()?,
+            },
+            ConditionModel::Eval { code } => Eval { code: code.into_vec() },
+            ConditionModel::Anon { cond_type, fingerprint, cost, subtypes } => Anon {
+                cond_type: condition_type_from_id(cond_type).map_err(|e| e.to_string())?,
+                fingerprint: fingerprint.into_vec(),
+                cost,
+                subtypes: subtypes.into_iter().collect(),
+            },
+        })
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ConditionModel::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Condition::try_from(ConditionModel::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// serialise condition into cbor
+#[cfg(feature = "cbor")]
+pub fn to_cbor(cond: &Condition) -> Vec<u8> {
+    serde_cbor::to_vec(cond).expect("cbor encoding broke")
+}
+
+/// read condition from cbor
+#[cfg(feature = "cbor")]
+pub fn from_cbor(buf: &[u8]) -> Result<Condition, ConditionDecodeError> {
+    serde_cbor::from_slice(buf).map_err(|e| ConditionDecodeError::Malformed(e.to_string()))
+}
+
+/// serialise condition into bincode, a compact format for internal storage (not for the wire)
+#[cfg(feature = "bincode")]
+pub fn to_bincode(cond: &Condition) -> Vec<u8> {
+    bincode::serialize(cond).expect("bincode encoding broke")
+}
+
+/// read condition from bincode
+#[cfg(feature = "bincode")]
+pub fn from_bincode(buf: &[u8]) -> Result<Condition, ConditionDecodeError> {
+    bincode::deserialize(buf).map_err(|e| ConditionDecodeError::Malformed(e.to_string()))
+}
+
+#[cfg(all(test, any(feature = "cbor", feature = "bincode")))]
+mod tests {
+    use super::*;
+    use libsecp256k1::SecretKey;
+
+    // one of each variant, with and without the optional fields
+    fn all_variants() -> Vec<Condition> {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secret);
+        let mut signed = Secp256k1 { pubkey, signature: None };
+        signed.sign_secp256k1(&secret, &crate::verify::message_hash(b"msg")).unwrap();
+        let signature = match signed {
+            Secp256k1 { signature, .. } => signature,
+            _ => None,
+        };
+
+        vec![
+            Preimage { preimage: vec![1, 2, 3] },
+            Preimage { preimage: vec![] },
+            Eval { code: vec![0xe4, 0x01] },
+            Secp256k1 { pubkey, signature: None },
+            Secp256k1 { pubkey, signature },
+            Secp256k1Hash { pubkey_hash: Some(internal::ripemd_sha(&pubkey)), pubkey: None, signature: None },
+            Secp256k1Hash { pubkey_hash: None, pubkey: Some(pubkey), signature },
+            Prefix { prefix: vec![7], max_message_len: 32, subcondition: Box::new(Eval { code: vec![1] }) },
+            Threshold {
+                threshold: 1,
+                subconditions: vec![
+                    Secp256k1 { pubkey, signature },
+                    Threshold { threshold: 1, subconditions: vec![Eval { code: vec![2] }] }.to_anon(),
+                    Preimage { preimage: vec![9] }.to_anon()
+                ]
+            },
+        ]
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_round_trip() {
+        for cond in all_variants() {
+            assert_eq!(from_cbor(&to_cbor(&cond)), Ok(cond));
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_byte_strings() {
+        // major type 2 (byte string) of length 3
+        assert!(to_cbor(&Preimage { preimage: vec![1, 2, 3] }).ends_with(&[0x43, 1, 2, 3]));
+        assert!(from_cbor(&[0xa0]).is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_round_trip() {
+        for cond in all_variants() {
+            assert_eq!(from_bincode(&to_bincode(&cond)), Ok(cond));
+        }
+        assert!(from_bincode(&[0xff]).is_err());
+    }
+}
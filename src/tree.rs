@@ -0,0 +1,788 @@
+use libsecp256k1::PublicKey;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::*;
+use crate::condition::internal::ripemd_sha;
+
+// Index paths address nodes in a condition tree: each element is the index into
+// the subconditions of a threshold (or 0 for the subcondition of a prefix).
+// The empty path is the root condition itself.
+
+/// direct subconditions of a threshold or prefix condition, empty for leaves
+pub fn children(cond: &Condition) -> &[Condition] {
+    match cond {
+        Threshold { subconditions, .. } => subconditions,
+        Prefix { subcondition, .. } => std::slice::from_ref(&**subcondition),
+        _ => &[],
+    }
+}
+
+pub fn children_mut(cond: &mut Condition) -> &mut [Condition] {
+    match cond {
+        Threshold { subconditions, .. } => subconditions,
+        Prefix { subcondition, .. } => std::slice::from_mut(&mut **subcondition),
+        _ => &mut [],
+    }
+}
+
+/// get the node at an index path
+pub fn node_at<'a>(cond: &'a Condition, path: &[usize]) -> Option<&'a Condition> {
+    match path.split_first() {
+        None => Some(cond),
+        Some((i, rest)) => children(cond).get(*i).and_then(|c| node_at(c, rest)),
+    }
+}
+
+pub fn node_at_mut<'a>(cond: &'a mut Condition, path: &[usize]) -> Option<&'a mut Condition> {
+    match path.split_first() {
+        None => Some(cond),
+        Some((i, rest)) => children_mut(cond).get_mut(*i).and_then(|c| node_at_mut(c, rest)),
+    }
+}
+
+/// all leaves (nodes other than threshold and prefix) with their index paths, depth first
+pub fn leaves(cond: &Condition) -> Vec<(Vec<usize>, &Condition)> {
+    let mut out = Vec::new();
+    collect_leaves(cond, &mut Vec::new(), &mut out);
+    out
+}
+
+fn collect_leaves<'a>(cond: &'a Condition, path: &mut Vec<usize>, out: &mut Vec<(Vec<usize>, &'a Condition)>) {
+    match cond {
+        Threshold { .. } | Prefix { .. } => {
+            for (i, c) in children(cond).iter().enumerate() {
+                path.push(i);
+                collect_leaves(c, path, out);
+                path.pop();
+            }
+        },
+        _ => out.push((path.clone(), cond)),
+    }
+}
+
+/// paths of the not yet signed secp256k1 and secp256k1hash leaves which could be signed with the key of my_pubkey
+pub fn signable_paths(cond: &Condition, my_pubkey: &PublicKey) -> Vec<Vec<usize>> {
+    let my_pubkey_hash = ripemd_sha(my_pubkey);
+    leaves(cond)
+        .into_iter()
+        .filter(|(_, leaf)| match leaf {
+            Secp256k1 { pubkey, signature: None } => pubkey == my_pubkey,
+            Secp256k1Hash { pubkey_hash: Some(pubkey_hash), signature: None, .. } => *pubkey_hash == my_pubkey_hash,
+            _ => false,
+        })
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// pubkeys of the signed secp256k1 and secp256k1hash leaves
+pub fn signers(cond: &Condition) -> Vec<PublicKey> {
+    leaves(cond)
+        .into_iter()
+        .filter_map(|(_, leaf)| match leaf {
+            Secp256k1 { pubkey, signature: Some(_) } => Some(*pubkey),
+            Secp256k1Hash { pubkey: Some(pubkey), signature: Some(_), .. } => Some(*pubkey),
+            _ => None,
+        })
+        .collect()
+}
+
+/// paths of two signed secp256k1 or secp256k1hash leaves whose signatures have the same r, which happens
+/// when a nonce was reused and gives away the private key. The first such pair in depth first order
+pub fn check_nonce_reuse(cond: &Condition) -> Option<(Vec<usize>, Vec<usize>)> {
+    let mut seen: Vec<(Vec<u8>, Vec<usize>)> = Vec::new();
+    for (path, leaf) in leaves(cond) {
+        let signature = match leaf {
+            Secp256k1 { signature: Some(signature), .. } | Secp256k1Hash { signature: Some(signature), .. } => signature,
+            _ => continue,
+        };
+        let r = signature.serialize()[..32].to_vec();
+        if let Some((_, first)) = seen.iter().find(|(seen_r, _)| *seen_r == r) {
+            return Some((first.clone(), path));
+        }
+        seen.push((r, path));
+    }
+    None
+}
+
+/// whether every leaf is a preimage (through thresholds and prefixes), so no signature or eval is involved.
+/// Anon preimages, like the unfulfilled ones of a decoded fulfillment, count as preimages; other anon
+/// subtrees are not known to be hashlocks
+pub fn is_pure_hashlock(cond: &Condition) -> bool {
+    leaves(cond).into_iter().all(|(_, leaf)| matches!(leaf, Preimage { .. } | Anon { cond_type: PreimageType, .. }))
+}
+
+/// replace the preimage conditions with preimages of at least min_len bytes by their anon form, which has the
+/// fingerprint (sha256 of the preimage) and the cost (its length) but not the preimage. Returns the removed
+/// preimages, to store by fingerprint and pass back to verify_with_preimages
+pub fn externalize_preimages(cond: &mut Condition, min_len: usize) -> Vec<Vec<u8>> {
+    match cond {
+        Preimage { preimage } if preimage.len() >= min_len => {
+            let preimage = preimage.clone();
+            *cond = cond.to_anon();
+            vec![preimage]
+        },
+        _ => children_mut(cond).iter_mut().flat_map(|c| externalize_preimages(c, min_len)).collect(),
+    }
+}
+
+/// copy of cond where each threshold, also under prefixes, keeps exactly threshold fulfilled subconditions (the
+/// cheapest ones, like the encoder picks) and has the others in the anon form, signed or not. The fingerprint does
+/// not change. The MIXED_MODE encoding writes every fulfilled subcondition, so this makes its witness smaller; the
+/// default encoding already leaves the extra ones out
+pub fn trim_to_threshold(cond: &Condition) -> Condition {
+    match cond {
+        Threshold { threshold, subconditions } => {
+            let trimmed: Vec<Condition> = subconditions.iter().map(trim_to_threshold).collect();
+            let mut fulfilled: Vec<usize> = (0..trimmed.len()).filter(|i| trimmed[*i].encode_fulfillment(0).is_ok()).collect();
+            fulfilled.sort_by_key(|i| trimmed[*i].cost());
+            fulfilled.truncate(*threshold as usize);
+            Threshold {
+                threshold: *threshold,
+                subconditions: trimmed
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, c)| match fulfilled.contains(&i) {
+                        true => c,
+                        _ => c.to_anon(),
+                    })
+                    .collect(),
+            }
+        },
+        Prefix { prefix, max_message_len, subcondition } => Prefix {
+            prefix: prefix.to_vec(),
+            max_message_len: *max_message_len,
+            subcondition: Box::new(trim_to_threshold(subcondition)),
+        },
+        _ => cond.clone(),
+    }
+}
+
+/// paths of the subtrees which can't be satisfied in any fulfillment: thresholds with more required than
+/// satisfiable subconditions and prefixes of those. Only the topmost node of a dead subtree is listed, the
+/// root path if cond itself is dead. Eval nodes are taken as satisfiable, see dead_subconditions_with_eval
+pub fn dead_subconditions(cond: &Condition) -> Vec<Vec<usize>> {
+    dead_subconditions_with_eval(cond, &|_| true)
+}
+
+/// dead_subconditions with eval nodes dead when eval(code) is false, for eval code known to always fail
+pub fn dead_subconditions_with_eval(cond: &Condition, eval: &dyn Fn(&[u8]) -> bool) -> Vec<Vec<usize>> {
+    let mut out = Vec::new();
+    collect_dead(cond, eval, &mut Vec::new(), &mut out);
+    out
+}
+
+fn collect_dead(cond: &Condition, eval: &dyn Fn(&[u8]) -> bool, path: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+    if !satisfiable(cond, eval) {
+        out.push(path.clone());
+        return;
+    }
+    for (i, c) in children(cond).iter().enumerate() {
+        path.push(i);
+        collect_dead(c, eval, path, out);
+        path.pop();
+    }
+}
+
+// anon subtrees are not known to be dead
+fn satisfiable(cond: &Condition, eval: &dyn Fn(&[u8]) -> bool) -> bool {
+    match cond {
+        Threshold { threshold, subconditions } => {
+            subconditions.iter().filter(|c| satisfiable(c, eval)).count() >= *threshold as usize
+        },
+        Prefix { subcondition, .. } => satisfiable(subcondition, eval),
+        Eval { code } => eval(code),
+        _ => true,
+    }
+}
+
+/// sha256 of the structure of cond: threshold counts, prefixes, eval codes and the type of each leaf, with pubkeys,
+/// pubkey hashes, signatures and preimages left out. It stays the same when the keys of a policy change, when
+/// threshold subconditions are reordered (as decoding does) and when preimage and secp256k1 leaves are in the anon
+/// form, and changes with the policy. Other anon nodes count by their type only
+pub fn policy_hash(cond: &Condition) -> [u8; 32] {
+    let mut template = Vec::new();
+    write_policy(cond, &mut template);
+    let mut out = [0; 32];
+    out.copy_from_slice(&crate::condition::internal::sha256(template));
+    out
+}
+
+// length prefixed so different structures never write the same bytes. Threshold subconditions are written
+// sorted by their own bytes so their order does not count
+fn write_policy(cond: &Condition, out: &mut Vec<u8>) {
+    let write_bytes = |out: &mut Vec<u8>, bytes: &[u8]| {
+        out.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        out.extend_from_slice(bytes);
+    };
+    out.push(cond.get_type().id());
+    match cond {
+        Threshold { threshold, subconditions } => {
+            out.extend_from_slice(&threshold.to_be_bytes());
+            out.extend_from_slice(&(subconditions.len() as u64).to_be_bytes());
+            let mut written: Vec<Vec<u8>> = subconditions
+                .iter()
+                .map(|c| {
+                    let mut sub = Vec::new();
+                    write_policy(c, &mut sub);
+                    sub
+                })
+                .collect();
+            written.sort();
+            for sub in written {
+                out.extend_from_slice(&sub);
+            }
+            return;
+        },
+        Prefix { prefix, max_message_len, .. } => {
+            write_bytes(out, prefix);
+            out.extend_from_slice(&max_message_len.to_be_bytes());
+        },
+        Eval { code } => write_bytes(out, code),
+        // these write nothing more when full either, the others are marked as what they hold is unknown
+        Anon { cond_type: PreimageType, .. } | Anon { cond_type: Secp256k1Type, .. } | Anon { cond_type: Secp256k1HashType, .. } => {},
+        Anon { .. } => out.push(AnonType.id()),
+        Preimage { .. } | Secp256k1 { .. } | Secp256k1Hash { .. } => {},
+    }
+    for c in children(cond) {
+        write_policy(c, out);
+    }
+}
+
+/// fingerprints of every node of the tree (the root, thresholds and leaves) without duplicates, depth first
+pub fn all_fingerprints(cond: &Condition) -> Vec<Vec<u8>> {
+    let mut out = Vec::new();
+    collect_fingerprints(cond, &mut HashSet::new(), &mut out);
+    out
+}
+
+fn collect_fingerprints(cond: &Condition, seen: &mut HashSet<Vec<u8>>, out: &mut Vec<Vec<u8>>) {
+    let fingerprint = cond.fingerprint();
+    if seen.insert(fingerprint.clone()) {
+        out.push(fingerprint);
+    }
+    for c in children(cond) {
+        collect_fingerprints(c, seen, out);
+    }
+}
+
+/// subtypes allowed by a but not by b, and allowed by b but not by a
+pub fn subtypes_diff(a: &Condition, b: &Condition) -> (HashSet<ConditionType>, HashSet<ConditionType>) {
+    let (a_types, b_types) = (a.subtypes(), b.subtypes());
+    (
+        a_types.difference(&b_types).cloned().collect(),
+        b_types.difference(&a_types).cloned().collect(),
+    )
+}
+
+/// paths (in new) of the secp256k1 and secp256k1hash leaves which are signed in new but were not signed in old,
+/// for fulfillments of the same condition. Errors with ConditionMismatch if a node differs between the two.
+/// Anon subconditions of old count as unsigned
+pub fn signature_delta(old: &Condition, new: &Condition) -> Result<Vec<Vec<usize>>, VerifyError> {
+    Ok(reveal_report(old, new)?.signatures.into_iter().map(|(path, _)| path).collect())
+}
+
+/// secrets in a fulfillment which were not in the condition (or an earlier fulfillment) it fulfills, see reveal_report
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct RevealReport {
+    /// (path, preimage) of the preimages which were anon
+    pub preimages: Vec<(Vec<usize>, Vec<u8>)>,
+    /// (path, signer) of the signatures which were not there
+    pub signatures: Vec<(Vec<usize>, PublicKey)>,
+}
+
+/// what fulfillment reveals compared to condition, leaf by leaf with paths in fulfillment. condition can have
+/// any subtree (or the whole tree) as anon, errors with ConditionMismatch if a node differs between the two
+pub fn reveal_report(condition: &Condition, fulfillment: &Condition) -> Result<RevealReport, VerifyError> {
+    let mut report = RevealReport::default();
+    collect_reveals(condition, fulfillment, &mut Vec::new(), &mut report)?;
+    Ok(report)
+}
+
+fn signer(cond: &Condition) -> Option<PublicKey> {
+    match cond {
+        Secp256k1 { pubkey, signature: Some(_) } | Secp256k1Hash { pubkey: Some(pubkey), signature: Some(_), .. } => Some(*pubkey),
+        _ => None,
+    }
+}
+
+fn collect_reveals(old: &Condition, new: &Condition, path: &mut Vec<usize>, report: &mut RevealReport) -> Result<(), VerifyError> {
+    let (old_fingerprint, new_fingerprint) = (old.fingerprint(), new.fingerprint());
+    if old_fingerprint != new_fingerprint {
+        return Err(VerifyError::ConditionMismatch {
+            fulfillment_fingerprint: new_fingerprint,
+            condition_fingerprint: old_fingerprint,
+        });
+    }
+    let (old_children, new_children) = (children(old), children(new));
+    if old_children.is_empty() {
+        // a leaf, or an anon subcondition in old which new may have expanded
+        let old_anon = matches!(old, Anon { .. });
+        for (leaf_path, leaf) in leaves(new) {
+            let leaf_path = [&path[..], &leaf_path[..]].concat();
+            match (leaf, signer(leaf)) {
+                (Preimage { preimage }, _) if old_anon => report.preimages.push((leaf_path, preimage.clone())),
+                (_, Some(pubkey)) if signer(old).is_none() => report.signatures.push((leaf_path, pubkey)),
+                _ => {},
+            }
+        }
+    } else if old_children.len() == new_children.len() {
+        for (i, (o, n)) in old_children.iter().zip(new_children).enumerate() {
+            path.push(i);
+            collect_reveals(o, n, path, report)?;
+            path.pop();
+        }
+    }
+    // otherwise new has the subtree as anon, so nothing in it is revealed
+    Ok(())
+}
+
+/// type ids of the nodes of the tree (anon nodes by their cond_type)
+pub fn present_type_ids(cond: &Condition) -> HashSet<u8> {
+    let mut ids: HashSet<u8> = children(cond).iter().flat_map(present_type_ids).collect();
+    ids.insert(cond.get_type().id());
+    ids
+}
+
+/// number of levels of the tree, 1 for a leaf
+pub fn depth(cond: &Condition) -> usize {
+    1 + children(cond).iter().map(depth).max().unwrap_or(0)
+}
+
+/// accumulates condition shapes over many conditions, see summary()
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    conditions: u64,
+    total_depth: u64,
+    type_counts: BTreeMap<u8, u64>,
+    threshold_shapes: BTreeMap<(u16, usize), u64>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StatsSummary {
+    pub conditions: u64,
+    /// number of recorded conditions using each type id (see present_type_ids)
+    pub type_counts: BTreeMap<u8, u64>,
+    /// number of threshold nodes by (M, N), over all recorded conditions
+    pub threshold_shapes: BTreeMap<(u16, usize), u64>,
+    /// 0 if nothing was recorded
+    pub average_depth: f64,
+}
+
+impl Stats {
+    pub fn record(&mut self, cond: &Condition) {
+        self.conditions += 1;
+        self.total_depth += depth(cond) as u64;
+        for id in present_type_ids(cond) {
+            *self.type_counts.entry(id).or_insert(0) += 1;
+        }
+        self.record_thresholds(cond);
+    }
+
+    fn record_thresholds(&mut self, cond: &Condition) {
+        if let Threshold { threshold, subconditions } = cond {
+            *self.threshold_shapes.entry((*threshold, subconditions.len())).or_insert(0) += 1;
+        }
+        for c in children(cond) {
+            self.record_thresholds(c);
+        }
+    }
+
+    pub fn summary(&self) -> StatsSummary {
+        StatsSummary {
+            conditions: self.conditions,
+            type_counts: self.type_counts.clone(),
+            threshold_shapes: self.threshold_shapes.clone(),
+            average_depth: match self.conditions {
+                0 => 0.0,
+                n => self.total_depth as f64 / n as f64,
+            },
+        }
+    }
+}
+
+/// first 4 bytes of the fingerprint in hex, to tell nodes apart in logs and diagrams
+pub fn short_id(cond: &Condition) -> String {
+    let fingerprint = cond.fingerprint();
+    // anon fingerprints are whatever was decoded and can be shorter
+    hex::encode(&fingerprint[..fingerprint.len().min(4)])
+}
+
+fn node_label(cond: &Condition) -> String {
+    let label = match cond {
+        Threshold { threshold, subconditions } => format!("threshold {}-of-{}", threshold, subconditions.len()),
+        Preimage { .. } => "preimage".into(),
+        Prefix { .. } => "prefix".into(),
+        Secp256k1 { signature: Some(_), .. } => "secp256k1 (signed)".into(),
+        Secp256k1 { .. } => "secp256k1".into(),
+        Secp256k1Hash { signature: Some(_), .. } => "secp256k1hash (signed)".into(),
+        Secp256k1Hash { .. } => "secp256k1hash".into(),
+        Eval { .. } => "eval".into(),
+        Anon { cond_type, .. } => format!("anon {}", cond_type.name()),
+    };
+    format!("{} {}", label, short_id(cond))
+}
+
+/// indented diagram of the condition tree, one node per line:
+///
+/// ```text
+/// threshold 2-of-3 1a2b3c4d
+/// ├─ secp256k1 5e6f7a8b
+/// └─ threshold 1-of-2 9c0d1e2f
+///    └─ eval 3a4b5c6d
+/// ```
+pub fn render_tree(cond: &Condition) -> String {
+    let mut out = node_label(cond);
+    out.push('\n');
+    render_children(cond, "", &mut out);
+    out
+}
+
+fn render_children(cond: &Condition, indent: &str, out: &mut String) {
+    let subconditions = children(cond);
+    for (i, c) in subconditions.iter().enumerate() {
+        let last = i + 1 == subconditions.len();
+        out.push_str(indent);
+        out.push_str(if last { "└─ " } else { "├─ " });
+        out.push_str(&node_label(c));
+        out.push('\n');
+        render_children(c, &format!("{}{}", indent, if last { "   " } else { "│  " }), out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsecp256k1::SecretKey;
+
+    fn pubkey(secret: u8) -> PublicKey {
+        PublicKey::from_secret_key(&SecretKey::parse(&[secret; 32]).unwrap())
+    }
+
+    #[test]
+    fn test_signable_paths_nested() {
+        let mut cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                Threshold {
+                    threshold: 1,
+                    subconditions: vec![
+                        Secp256k1 { pubkey: pubkey(2), signature: None },
+                        Secp256k1Hash { pubkey_hash: Some(ripemd_sha(&pubkey(1))), pubkey: None, signature: None }
+                    ]
+                },
+                Eval { code: vec![0xe4] }
+            ]
+        };
+
+        assert_eq!(signable_paths(&cond, &pubkey(1)), vec![vec![0], vec![1, 1]]);
+        assert_eq!(signable_paths(&cond, &pubkey(2)), vec![vec![1, 0]]);
+        assert!(signable_paths(&cond, &pubkey(3)).is_empty());
+
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        node_at_mut(&mut cond, &[0]).unwrap().sign_secp256k1(&secret, &crate::verify::message_hash(b"msg")).unwrap();
+        assert_eq!(signable_paths(&cond, &pubkey(1)), vec![vec![1, 1]]);
+    }
+
+    #[test]
+    fn test_is_pure_hashlock() {
+        let hashlock = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                Preimage { preimage: vec![1] },
+                Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![2] }] }
+            ]
+        };
+        let mixed = Threshold {
+            threshold: 1,
+            subconditions: vec![Preimage { preimage: vec![1] }, Secp256k1 { pubkey: pubkey(1), signature: None }]
+        };
+
+        assert!(is_pure_hashlock(&hashlock));
+        assert!(is_pure_hashlock(&Preimage { preimage: vec![1] }));
+        assert!(!is_pure_hashlock(&mixed));
+        assert!(!is_pure_hashlock(&Threshold { threshold: 1, subconditions: vec![Eval { code: vec![0xe4] }] }));
+        assert!(!is_pure_hashlock(&hashlock.to_anon()));
+
+        // the unfulfilled preimage is decoded as anon
+        let one_of_two = Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![1] }, Preimage { preimage: vec![2; 8] }] };
+        let decoded = decode_fulfillment(&one_of_two.encode_fulfillment(0).unwrap(), 0).unwrap();
+        assert!(children(&decoded).iter().any(|c| matches!(c, Anon { .. })));
+        assert!(is_pure_hashlock(&decoded));
+        assert!(!is_pure_hashlock(&Threshold { threshold: 1, subconditions: vec![Preimage { preimage: vec![1] }, Eval { code: vec![1] }.to_anon()] }));
+    }
+
+    #[test]
+    fn test_trim_to_threshold() {
+        let secret = |i| SecretKey::parse(&[i; 32]).unwrap();
+        let mut cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                Preimage { preimage: vec![1] },
+                Secp256k1 { pubkey: pubkey(2), signature: None }
+            ]
+        };
+        assert_eq!(cond.sign_multi(&[secret(1), secret(2)], b"msg"), 2);
+
+        let trimmed = trim_to_threshold(&cond);
+        let fulfilled: Vec<&Condition> = children(&trimmed).iter().filter(|c| c.encode_fulfillment(0).is_ok()).collect();
+        // the preimage and the first of the same cost signatures
+        assert_eq!(fulfilled, vec![&children(&cond)[0], &children(&cond)[1]]);
+        assert_eq!(children(&trimmed)[2], children(&cond)[2].to_anon());
+        assert_eq!(trimmed.fingerprint(), cond.fingerprint());
+        assert_eq!(crate::verify(&trimmed, b"msg"), Ok(()));
+        assert_eq!(trimmed.encode_fulfillment(0), cond.encode_fulfillment(0));
+
+        // the mixed mode encoding of the over-signed threshold has all three fulfillments, the trimmed one two
+        let mixed = cond.encode_fulfillment(MIXED_MODE).unwrap();
+        let trimmed_mixed = trimmed.encode_fulfillment(MIXED_MODE).unwrap();
+        assert!(trimmed_mixed.len() < mixed.len());
+        let decoded = crate::decode_fulfillment(&trimmed_mixed, MIXED_MODE).unwrap();
+        assert_eq!(children(&decoded).iter().filter(|c| c.encode_fulfillment(0).is_ok()).count(), 2);
+        assert_eq!(decoded.fingerprint(), cond.fingerprint());
+
+        // thresholds under a prefix are trimmed too
+        let prefixed = Prefix { prefix: vec![1], max_message_len: 32, subcondition: Box::new(cond.clone()) };
+        match trim_to_threshold(&prefixed) {
+            Prefix { subcondition, .. } => assert_eq!(*subcondition, trimmed),
+            _ => panic!("not a prefix"),
+        }
+    }
+
+    #[test]
+    fn test_subtypes_diff() {
+        let secp256k1_only = Threshold {
+            threshold: 1,
+            subconditions: vec![Secp256k1 { pubkey: pubkey(1), signature: None }]
+        };
+        let with_eval = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(2), signature: None },
+                Eval { code: vec![0xe4] }
+            ]
+        };
+        let eval_only: HashSet<ConditionType> = vec![EvalType].into_iter().collect();
+
+        assert_eq!(subtypes_diff(&secp256k1_only, &with_eval), (HashSet::new(), eval_only.clone()));
+        assert_eq!(subtypes_diff(&with_eval, &secp256k1_only.to_anon()), (eval_only, HashSet::new()));
+        assert_eq!(subtypes_diff(&with_eval, &with_eval), (HashSet::new(), HashSet::new()));
+    }
+
+    #[test]
+    fn test_all_fingerprints() {
+        let inner = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                Eval { code: vec![0xe4] }
+            ]
+        };
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                inner.clone(),
+                Eval { code: vec![0xe4] },
+                inner.to_anon()
+            ]
+        };
+        let fingerprints = all_fingerprints(&cond);
+
+        // root, secp256k1, inner threshold (also as anon), eval
+        assert_eq!(fingerprints.len(), 4);
+        assert_eq!(fingerprints[0], cond.fingerprint());
+        assert!(fingerprints.contains(&inner.fingerprint()));
+    }
+
+    #[test]
+    fn test_render_tree_nested() {
+        let inner = Threshold {
+            threshold: 1,
+            subconditions: vec![Eval { code: vec![0xe4] }, Preimage { preimage: vec![9] }]
+        };
+        let secp256k1 = Secp256k1 { pubkey: pubkey(1), signature: None };
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![secp256k1.clone(), inner.clone(), Preimage { preimage: vec![1, 2, 3] }]
+        };
+
+        let expected = format!(
+            "threshold 2-of-3 {}\n\
+             ├─ secp256k1 {}\n\
+             ├─ threshold 1-of-2 {}\n\
+             │  ├─ eval 5e1effe9\n\
+             │  └─ preimage 2b4c342f\n\
+             └─ preimage 039058c6\n",
+            short_id(&cond), short_id(&secp256k1), short_id(&inner));
+        assert_eq!(render_tree(&cond), expected);
+    }
+
+    #[test]
+    fn test_signature_delta() {
+        let secret = |i| SecretKey::parse(&[i; 32]).unwrap();
+        let mut partial = Threshold {
+            threshold: 3,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                Threshold {
+                    threshold: 1,
+                    subconditions: vec![
+                        Secp256k1Hash { pubkey_hash: Some(ripemd_sha(&pubkey(2))), pubkey: None, signature: None },
+                        Eval { code: vec![0xe4] }
+                    ]
+                },
+                Secp256k1 { pubkey: pubkey(3), signature: None }
+            ]
+        };
+        let () = partial.sign_secp256k1(&secret(1), &crate::verify::message_hash(b"msg")).unwrap();
+        let mut complete = partial.clone();
+        assert_eq!(complete.sign_multi(&[secret(2), secret(3)], b"msg"), 2);
+
+        assert_eq!(signature_delta(&partial, &complete), Ok(vec![vec![1, 0], vec![2]]));
+        assert_eq!(signature_delta(&complete, &complete), Ok(vec![]));
+        // old version with the inner threshold as anon
+        let mut anon_inner = partial.clone();
+        children_mut(&mut anon_inner)[1] = children(&partial)[1].to_anon();
+        assert_eq!(signature_delta(&anon_inner, &complete), Ok(vec![vec![1, 0], vec![2]]));
+
+        let other = Threshold { threshold: 1, subconditions: vec![Eval { code: vec![1] }] };
+        assert!(matches!(signature_delta(&partial, &other), Err(VerifyError::ConditionMismatch { .. })));
+    }
+
+    #[test]
+    fn test_stats() {
+        let two_of_three = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                Threshold { threshold: 1, subconditions: vec![Eval { code: vec![1] }, Preimage { preimage: vec![1] }] },
+                Eval { code: vec![2] }.to_anon(),
+            ]
+        };
+        let mut stats = Stats::default();
+        assert_eq!(stats.summary().average_depth, 0.0);
+        stats.record(&two_of_three);
+        stats.record(&Preimage { preimage: vec![2] });
+        stats.record(&Threshold { threshold: 1, subconditions: vec![Eval { code: vec![3] }] });
+
+        let summary = stats.summary();
+        assert_eq!(summary.conditions, 3);
+        assert_eq!(summary.type_counts, vec![(0, 2), (2, 2), (5, 1), (15, 2)].into_iter().collect());
+        assert_eq!(summary.threshold_shapes, vec![((1, 1), 1), ((1, 2), 1), ((2, 3), 1)].into_iter().collect());
+        assert_eq!(summary.average_depth, 2.0);
+        assert_eq!(present_type_ids(&two_of_three), vec![0, 2, 5, 15].into_iter().collect());
+        assert_eq!(depth(&two_of_three), 3);
+    }
+
+    #[test]
+    fn test_check_nonce_reuse() {
+        use libsecp256k1::Signature;
+        let signature = |r: u8, s: u8| Some(Signature::parse_standard_slice(&[&[r; 32][..], &[s; 32][..]].concat()).unwrap());
+        let mut cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: signature(1, 2) },
+                Threshold {
+                    threshold: 1,
+                    subconditions: vec![
+                        Secp256k1 { pubkey: pubkey(2), signature: signature(3, 4) },
+                        Secp256k1Hash { pubkey_hash: None, pubkey: Some(pubkey(3)), signature: signature(1, 5) },
+                    ]
+                },
+            ]
+        };
+
+        assert_eq!(check_nonce_reuse(&cond), Some((vec![0], vec![1, 1])));
+        *node_at_mut(&mut cond, &[1, 1]).unwrap() = Secp256k1 { pubkey: pubkey(3), signature: signature(5, 2) };
+        // same s is not a reuse
+        assert_eq!(check_nonce_reuse(&cond), None);
+    }
+
+    #[test]
+    fn test_dead_subconditions() {
+        let impossible = Threshold {
+            threshold: 3,
+            subconditions: vec![Secp256k1 { pubkey: pubkey(2), signature: None }, Eval { code: vec![0] }]
+        };
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                impossible.clone(),
+                Prefix { prefix: vec![1], max_message_len: 32, subcondition: Box::new(impossible.clone()) },
+                Threshold { threshold: 1, subconditions: vec![Eval { code: vec![0] }, Eval { code: vec![1] }] },
+            ]
+        };
+        let eval = |code: &[u8]| code != &[0][..];
+
+        assert_eq!(dead_subconditions(&cond), vec![vec![1], vec![2]]);
+        assert_eq!(dead_subconditions_with_eval(&cond, &eval), vec![vec![1], vec![2], vec![3, 0]]);
+        assert_eq!(dead_subconditions(&impossible), vec![vec![]]);
+        assert_eq!(dead_subconditions(&impossible.to_anon()), Vec::<Vec<usize>>::new());
+    }
+
+    #[test]
+    fn test_policy_hash() {
+        let policy = |a: u8, b: u8, threshold: u16, code: u8| Threshold {
+            threshold,
+            subconditions: vec![
+                Eval { code: vec![code] },
+                Secp256k1 { pubkey: pubkey(a), signature: None },
+                Secp256k1Hash { pubkey_hash: Some(ripemd_sha(&pubkey(b))), pubkey: None, signature: None },
+            ]
+        };
+        let hash = policy_hash(&policy(1, 2, 2, 0xe4));
+
+        assert_eq!(policy_hash(&policy(3, 4, 2, 0xe4)), hash);
+        assert_ne!(policy_hash(&policy(1, 2, 1, 0xe4)), hash);
+        assert_ne!(policy_hash(&policy(1, 2, 2, 0xe5)), hash);
+        let mut reordered = policy(1, 2, 2, 0xe4);
+        children_mut(&mut reordered).swap(1, 2);
+        assert_eq!(policy_hash(&reordered), hash);
+        // decoding reorders the subconditions and has the unfulfilled secp256k1hash leaf as anon
+        for (a, b) in vec![(1, 2), (3, 4)] {
+            let mut signed = policy(a, b, 2, 0xe4);
+            assert_eq!(signed.sign_multi(&[SecretKey::parse(&[a; 32]).unwrap()], b"msg"), 1);
+            let decoded = crate::decode_fulfillment(&signed.encode_fulfillment(0).unwrap(), 0).unwrap();
+            assert_ne!(children(&decoded), children(&signed));
+            assert_eq!(policy_hash(&decoded), hash);
+        }
+        // signing does not change the policy
+        let mut signed = policy(1, 2, 2, 0xe4);
+        let _ = signed.sign_multi(&[SecretKey::parse(&[1; 32]).unwrap()], b"msg");
+        assert_eq!(policy_hash(&signed), hash);
+    }
+
+    #[test]
+    fn test_reveal_report() {
+        let secret = |i| SecretKey::parse(&[i; 32]).unwrap();
+        // hashlock or 2-of-3 multisig
+        let mut fulfillment = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                Preimage { preimage: b"secret".to_vec() },
+                Threshold {
+                    threshold: 2,
+                    subconditions: vec![
+                        Secp256k1 { pubkey: pubkey(1), signature: None },
+                        Secp256k1 { pubkey: pubkey(2), signature: None },
+                        Secp256k1Hash { pubkey_hash: Some(ripemd_sha(&pubkey(3))), pubkey: None, signature: None },
+                    ]
+                },
+            ]
+        };
+        let (condition, unsigned) = (fulfillment.to_anon(), fulfillment.clone());
+        assert_eq!(fulfillment.sign_multi(&[secret(1), secret(3)], b"msg"), 2);
+
+        assert_eq!(reveal_report(&condition, &fulfillment), Ok(RevealReport {
+            preimages: vec![(vec![0], b"secret".to_vec())],
+            signatures: vec![(vec![1, 0], pubkey(1)), (vec![1, 2], pubkey(3))],
+        }));
+        // against the unsigned structure the preimage was already known
+        let report = reveal_report(&unsigned, &fulfillment).unwrap();
+        assert_eq!((report.preimages, report.signatures.len()), (vec![], 2));
+        assert!(reveal_report(&Preimage { preimage: vec![1] }, &fulfillment).is_err());
+    }
+}
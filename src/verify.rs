@@ -0,0 +1,753 @@
+use libsecp256k1::{Message, PublicKey, Signature};
+
+use crate::*;
+use crate::condition::internal::{sha256, ripemd_sha};
+
+#[derive(PartialEq, Debug)]
+pub enum VerifyError {
+    /// the fulfillment does not meet its threshold(s)
+    Unfulfilled,
+    /// the secp256k1 signature of the leaf at this index path does not verify against the message
+    /// (the path is empty for a single leaf or a detached signature)
+    InvalidSignature(Vec<usize>),
+    /// a secp256k1hash pubkey does not hash to its pubkey_hash
+    PubkeyHashMismatch,
+    /// a prefix condition needs the raw message, it can't be verified from a hash
+    PrefixNeedsMessage,
+    /// the message is longer than a prefix condition max_message_len
+    MessageTooLong,
+    /// a required signer has not signed the fulfillment
+    MissingRequiredSigner(PublicKey),
+    /// no secp256k1 or secp256k1hash leaf has this pubkey
+    NoMatchingLeaf(PublicKey),
+    /// the fulfillment or condition could not be decoded
+    Decode(ConditionDecodeError),
+    /// the fulfillment is not a fulfillment of the expected condition
+    ConditionMismatch { fulfillment_fingerprint: Vec<u8>, condition_fingerprint: Vec<u8> },
+    /// the preimage resolved for the anon preimage condition at this path does not match its fingerprint or length
+    PreimageMismatch(Vec<usize>),
+    /// the condition declares a subtype which no subcondition of the fulfillment uses
+    OverclaimedSubtype(ConditionType),
+    /// a subcondition of the fulfillment uses a type which the condition does not declare
+    UnderclaimedSubtype(ConditionType),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VerifyError::Unfulfilled => write!(f, "Fulfillment does not meet its threshold"),
+            VerifyError::InvalidSignature(path) => write!(f, "Invalid signature at {:?}", path),
+            VerifyError::PubkeyHashMismatch => write!(f, "Pubkey does not match pubkey hash"),
+            VerifyError::PrefixNeedsMessage => write!(f, "Prefix condition needs the message"),
+            VerifyError::MessageTooLong => write!(f, "Message too long for prefix condition"),
+            VerifyError::MissingRequiredSigner(pubkey) => {
+                write!(f, "Missing required signer {}", hex::encode(&pubkey.serialize_compressed()[..]))
+            },
+            VerifyError::NoMatchingLeaf(pubkey) => {
+                write!(f, "No leaf for pubkey {}", hex::encode(&pubkey.serialize_compressed()[..]))
+            },
+            VerifyError::Decode(e) => write!(f, "{}", e),
+            VerifyError::ConditionMismatch { fulfillment_fingerprint, condition_fingerprint } => write!(
+                f,
+                "Fulfillment with fingerprint {} is not for condition with fingerprint {}",
+                hex::encode(fulfillment_fingerprint),
+                hex::encode(condition_fingerprint)
+            ),
+            VerifyError::PreimageMismatch(path) => write!(f, "Resolved preimage does not match at {:?}", path),
+            VerifyError::OverclaimedSubtype(t) => write!(f, "Subtype {} declared but not used", t.name()),
+            VerifyError::UnderclaimedSubtype(t) => write!(f, "Subtype {} used but not declared", t.name()),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VerifyError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type R<T> = Result<T, VerifyError>;
+
+/// result of verifying one item of verify_batch
+pub type VerifyOutcome = R<()>;
+
+/// verify fulfillment signatures against sha256(msg)
+pub fn verify(cond: &Condition, msg: &[u8]) -> R<()> {
+    verify_with_eval(cond, msg, &no_eval)
+}
+
+/// verify like verify(), with eval nodes satisfied when eval(code) returns true
+/// (verify() does not run eval code, eval nodes are never satisfied there). A true eval counts to its
+/// threshold like a valid signature
+pub fn verify_with_eval(cond: &Condition, msg: &[u8], eval: &dyn Fn(&[u8]) -> bool) -> R<()> {
+    let mut hooks = VerifyHooks { eval, resolve_preimage: None };
+    check_fulfilled(verify_node(cond, &mut Vec::new(), Some(msg), &message_hash(msg), &mut hooks)?)
+}
+
+/// chain state for eval code, see verify_with_eval_context. The default has no height or time, so timelocked
+/// evals checking them with height_reached and time_reached fail
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct EvalContext {
+    pub height: Option<u64>,
+    pub time: Option<u64>,
+}
+
+impl EvalContext {
+    /// whether the block height is known and at least height
+    pub fn height_reached(&self, height: u64) -> bool {
+        self.height.map_or(false, |h| h >= height)
+    }
+
+    /// whether the block time is known and at least time
+    pub fn time_reached(&self, time: u64) -> bool {
+        self.time.map_or(false, |t| t >= time)
+    }
+}
+
+/// verify like verify_with_eval(), with eval(code, ctx) getting the chain context, for timelocked eval code
+pub fn verify_with_eval_context(cond: &Condition, msg: &[u8], ctx: &EvalContext, eval: &dyn Fn(&[u8], &EvalContext) -> bool) -> R<()> {
+    verify_with_eval(cond, msg, &|code| eval(code, ctx))
+}
+
+/// verify like verify(), with preimages kept outside the condition (see externalize_preimages):
+/// anon preimage conditions are satisfied by resolve(fingerprint), which must return the preimage
+/// with this fingerprint (sha256) and length (cost), or None if it is not known
+pub fn verify_with_preimages(cond: &Condition, msg: &[u8], resolve: &mut dyn FnMut(&[u8]) -> Option<Vec<u8>>) -> R<()> {
+    let mut hooks = VerifyHooks { eval: &no_eval, resolve_preimage: Some(resolve) };
+    check_fulfilled(verify_node(cond, &mut Vec::new(), Some(msg), &message_hash(msg), &mut hooks)?)
+}
+
+/// verify many independent (fulfillment, message) items like verify(), in parallel across items with the rayon feature.
+/// The outcomes are in the order of items
+pub fn verify_batch(items: &[(Condition, Vec<u8>)]) -> Vec<VerifyOutcome> {
+    #[cfg(feature = "rayon")]
+    let outcomes = {
+        use rayon::prelude::*;
+        items.par_iter().map(|(cond, msg)| verify(cond, msg)).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let outcomes = items.iter().map(|(cond, msg)| verify(cond, msg)).collect();
+    outcomes
+}
+
+/// verify fulfillment signatures against an already computed message hash.
+/// msg_hash is used as-is: it is not hashed again so it must be the exact 32 bytes the signers signed
+/// (the same value passed as the Message to sign_secp256k1).
+/// For komodo cc spends it is the sighash of the spending input, which binds the condition. There is no variant
+/// taking the transaction: the crate has no transaction parser and the sighash rules belong to the chain code
+pub fn verify_prehashed(cond: &Condition, msg_hash: &[u8; 32]) -> R<()> {
+    let mut hooks = VerifyHooks { eval: &no_eval, resolve_preimage: None };
+    check_fulfilled(verify_node(cond, &mut Vec::new(), None, &Message::parse(msg_hash), &mut hooks)?)
+}
+
+/// verify like verify() and also require each of required_signers to have a valid signature in the fulfillment,
+/// for policies like "alice must always sign" on top of the threshold
+pub fn verify_with_required_signers(cond: &Condition, msg: &[u8], required_signers: &[PublicKey]) -> R<()> {
+    let () = verify(cond, msg)?;
+    // all present signatures are valid once verify passed
+    let signers = signers(cond);
+    match required_signers.iter().find(|pubkey| !signers.contains(pubkey)) {
+        Some(pubkey) => Err(VerifyError::MissingRequiredSigner(*pubkey)),
+        None => Ok(()),
+    }
+}
+
+/// verify an interledger fulfillment against its condition in the raw form of ilp packets: the 32 byte preimage
+/// and its sha256, no message is involved. DER encoded preimage conditions and fulfillments (any length) go through
+/// decode_fulfillment and verify as usual
+pub fn ilp_verify_preimage(fulfillment: &[u8], condition: &[u8]) -> R<()> {
+    if fulfillment.len() != 32 || condition.len() != 32 {
+        return Err(VerifyError::Decode(ConditionDecodeError::Malformed("ILP fulfillment and condition are 32 bytes".into())));
+    }
+    match sha256(fulfillment.to_vec()) == condition {
+        true => Ok(()),
+        _ => Err(VerifyError::PreimageMismatch(vec![])),
+    }
+}
+
+/// check fulfillment is a fulfillment of condition (the full structure or the anon form): both are equal once anonymized.
+/// This does not verify signatures
+pub fn assert_fulfills(fulfillment: &Condition, condition: &Condition) -> R<()> {
+    match fulfillment.to_anon() == condition.to_anon() {
+        true => Ok(()),
+        _ => Err(VerifyError::ConditionMismatch {
+            fulfillment_fingerprint: fulfillment.fingerprint(),
+            condition_fingerprint: condition.fingerprint(),
+        }),
+    }
+}
+
+/// verify the signatures of fulfillment against sha256(msg) and then check it fulfills the condition returned
+/// by fetch_condition, for conditions which are looked up (like from the chain) only when needed.
+/// fetch_condition is called after verification passed and not at all if it failed, its error is returned as is
+pub fn verify_with_fetched_condition(fulfillment: &Condition, msg: &[u8], fetch_condition: impl FnOnce() -> R<Condition>) -> R<()> {
+    let () = verify(fulfillment, msg)?;
+    assert_fulfills(fulfillment, &fetch_condition()?)
+}
+
+/// check the subtypes declared by condition (usually decoded, so in the anon form) are exactly the types used
+/// below the fulfillment. Subtypes are not part of the threshold fingerprint, so a crafted condition can
+/// over-claim types to pass subtypes based policy filters, or under-claim them to hide eval nodes
+pub fn check_subtypes(fulfillment: &Condition, condition: &Condition) -> R<()> {
+    let (underclaimed, overclaimed) = subtypes_diff(fulfillment, condition);
+    if let Some(t) = overclaimed.into_iter().min_by_key(ConditionType::id) {
+        return Err(VerifyError::OverclaimedSubtype(t));
+    }
+    match underclaimed.into_iter().min_by_key(ConditionType::id) {
+        Some(t) => Err(VerifyError::UnderclaimedSubtype(t)),
+        None => Ok(()),
+    }
+}
+
+/// the message each secp256k1 and secp256k1hash leaf signs (and is verified against, hashed with sha256),
+/// with its path. By default it is msg for every leaf, except below prefix conditions which prepend their prefix
+pub fn signing_messages(cond: &Condition, msg: &[u8]) -> Vec<(Vec<usize>, Vec<u8>)> {
+    signing_messages_with(cond, msg, &|_, msg| msg.to_vec())
+}
+
+/// signing_messages for cc designs where the eval context changes the message per leaf:
+/// sighash gets the leaf path and the default message of the leaf and returns the message to sign
+pub fn signing_messages_with(
+    cond: &Condition,
+    msg: &[u8],
+    sighash: &dyn Fn(&[usize], &[u8]) -> Vec<u8>,
+) -> Vec<(Vec<usize>, Vec<u8>)> {
+    let mut out = Vec::new();
+    collect_signing_messages(cond, &mut Vec::new(), msg, sighash, &mut out);
+    out
+}
+
+fn collect_signing_messages(
+    cond: &Condition,
+    path: &mut Vec<usize>,
+    msg: &[u8],
+    sighash: &dyn Fn(&[usize], &[u8]) -> Vec<u8>,
+    out: &mut Vec<(Vec<usize>, Vec<u8>)>,
+) {
+    match cond {
+        Secp256k1 { .. } | Secp256k1Hash { .. } => out.push((path.clone(), sighash(path, msg))),
+        Prefix { prefix, subcondition, .. } => {
+            let prefixed = [&prefix[..], msg].concat();
+            path.push(0);
+            collect_signing_messages(subcondition, path, &prefixed, sighash, out);
+            path.pop();
+        },
+        _ => {
+            for (i, c) in children(cond).iter().enumerate() {
+                path.push(i);
+                collect_signing_messages(c, path, msg, sighash, out);
+                path.pop();
+            }
+        },
+    }
+}
+
+/// attach a detached signature (made by a hardware wallet or another airgapped signer) to every
+/// secp256k1 and secp256k1hash leaf of pubkey. If msg is set the signature is first verified against sha256(msg).
+/// Returns the number of updated leaves
+pub fn add_signature(cond: &mut Condition, pubkey: &PublicKey, signature: Signature, msg: Option<&[u8]>) -> R<usize> {
+    if let Some(msg) = msg {
+        let _ = verify_signature(&message_hash(msg), &signature, pubkey, &[])?;
+    }
+    match attach_signature(cond, pubkey, &ripemd_sha(pubkey), &signature) {
+        0 => Err(VerifyError::NoMatchingLeaf(*pubkey)),
+        updated => Ok(updated),
+    }
+}
+
+fn attach_signature(cond: &mut Condition, pubkey: &PublicKey, pubkey_hash: &[u8], signature: &Signature) -> usize {
+    match cond {
+        Secp256k1 { pubkey: leaf_pubkey, signature: leaf_signature } if *leaf_pubkey == *pubkey => {
+            *leaf_signature = Some(*signature);
+            1
+        },
+        Secp256k1Hash { pubkey_hash: Some(leaf_pubkey_hash), pubkey: leaf_pubkey, signature: leaf_signature }
+            if leaf_pubkey_hash.as_slice() == pubkey_hash => {
+            *leaf_pubkey = Some(*pubkey);
+            *leaf_signature = Some(*signature);
+            1
+        },
+        _ => children_mut(cond)
+            .iter_mut()
+            .map(|c| attach_signature(c, pubkey, pubkey_hash, signature))
+            .sum(),
+    }
+}
+
+/// check the hex encoded fulfillment is a fulfillment of the condition parsed from json
+/// (either the full structure or the anon form) and verify its signatures against sha256(msg)
+#[cfg(feature = "json")]
+pub fn verify_against_json(fulfillment_hex: &str, condition_json: &serde_json::Value, msg: &[u8]) -> R<()> {
+    let ffil_bin = hex::decode(fulfillment_hex)
+        .map_err(|e| VerifyError::Decode(ConditionDecodeError::Malformed(e.to_string())))?;
+    let fulfillment = decode_fulfillment(&ffil_bin, 0).map_err(VerifyError::Decode)?;
+    let condition = from_json(condition_json).map_err(VerifyError::Decode)?;
+    let () = assert_fulfills(&fulfillment, &condition)?;
+    verify(&fulfillment, msg)
+}
+
+/// number of signatures still needed to fulfill cond once eval nodes are resolved with eval(code),
+/// for thresholds where eval nodes that are always true lower the number of signers needed.
+/// Preimages count as revealed, anon subconditions as not fulfillable. u16::MAX if cond can't be fulfilled
+pub fn effective_threshold(cond: &Condition, eval: &dyn Fn(&[u8]) -> bool) -> u16 {
+    signatures_needed(cond, eval).map_or(u16::MAX, |n| n.min(u16::MAX as u64) as u16)
+}
+
+// None if the node can't be fulfilled
+fn signatures_needed(cond: &Condition, eval: &dyn Fn(&[u8]) -> bool) -> Option<u64> {
+    match cond {
+        Secp256k1 { .. } | Secp256k1Hash { .. } => Some(1),
+        Preimage { .. } => Some(0),
+        Eval { code } => match eval(code) {
+            true => Some(0),
+            _ => None,
+        },
+        Prefix { subcondition, .. } => signatures_needed(subcondition, eval),
+        Threshold { threshold, subconditions } => {
+            let mut needed: Vec<u64> = subconditions.iter().filter_map(|c| signatures_needed(c, eval)).collect();
+            if needed.len() < *threshold as usize {
+                return None;
+            }
+            needed.sort();
+            Some(needed.iter().take(*threshold as usize).sum())
+        },
+        Anon { .. } => None,
+    }
+}
+
+fn no_eval(_code: &[u8]) -> bool {
+    false
+}
+
+fn check_fulfilled(fulfilled: bool) -> R<()> {
+    match fulfilled {
+        true => Ok(()),
+        _ => Err(VerifyError::Unfulfilled),
+    }
+}
+
+pub(crate) fn message_hash(msg: &[u8]) -> Message {
+    Message::parse_slice(&sha256(msg.to_vec())).expect("sha256 is 32 bytes")
+}
+
+// callbacks for the nodes which verify_node can't check by itself
+struct VerifyHooks<'a> {
+    eval: &'a dyn Fn(&[u8]) -> bool,
+    resolve_preimage: Option<&'a mut dyn FnMut(&[u8]) -> Option<Vec<u8>>>,
+}
+
+// returns whether the node is satisfied, bad signatures are errors and not just unsatisfied nodes.
+// path is the index path of cond, for errors.
+// msg is the raw message if known, it is only needed for prefix conditions
+fn verify_node(cond: &Condition, path: &mut Vec<usize>, msg: Option<&[u8]>, hash: &Message, hooks: &mut VerifyHooks) -> R<bool> {
+    match cond {
+        Preimage { .. } => Ok(true),
+        Secp256k1 { pubkey, signature: Some(signature) } => verify_signature(hash, signature, pubkey, path),
+        Secp256k1Hash { pubkey_hash, pubkey: Some(pubkey), signature: Some(signature) } => {
+            if let Some(pubkey_hash) = pubkey_hash {
+                if *pubkey_hash != ripemd_sha(pubkey) {
+                    return Err(VerifyError::PubkeyHashMismatch);
+                }
+            }
+            verify_signature(hash, signature, pubkey, path)
+        },
+        Prefix { prefix, max_message_len, subcondition } => {
+            let msg = msg.ok_or(VerifyError::PrefixNeedsMessage)?;
+            if msg.len() as u64 > *max_message_len {
+                return Err(VerifyError::MessageTooLong);
+            }
+            let mut prefixed = prefix.to_vec();
+            prefixed.extend_from_slice(msg);
+            path.push(0);
+            let satisfied = verify_node(subcondition, path, Some(&prefixed), &message_hash(&prefixed), hooks)?;
+            path.pop();
+            Ok(satisfied)
+        },
+        Threshold { threshold, subconditions } => {
+            let mut satisfied = 0;
+            for (i, c) in subconditions.iter().enumerate() {
+                path.push(i);
+                if verify_node(c, path, msg, hash, hooks)? {
+                    satisfied += 1;
+                }
+                path.pop();
+            }
+            Ok(satisfied >= *threshold as usize)
+        },
+        // eval code is run by the caller, by default (in the cc module) not in signature verification
+        Eval { code } => Ok((hooks.eval)(code)),
+        Anon { cond_type: PreimageType, fingerprint, cost, .. } if hooks.resolve_preimage.is_some() => {
+            let resolve = hooks.resolve_preimage.as_mut().expect("checked above");
+            match resolve(fingerprint) {
+                Some(preimage) if preimage.len() as u64 == *cost && sha256(preimage.to_vec()) == *fingerprint => Ok(true),
+                Some(_) => Err(VerifyError::PreimageMismatch(path.clone())),
+                None => Ok(false),
+            }
+        },
+        // unsigned leaves and anon subconditions do not count to the threshold
+        Secp256k1 { .. } | Secp256k1Hash { .. } | Anon { .. } => Ok(false),
+    }
+}
+
+fn verify_signature(hash: &Message, signature: &Signature, pubkey: &PublicKey, path: &[usize]) -> R<bool> {
+    match libsecp256k1::verify(hash, signature, pubkey) {
+        true => Ok(true),
+        _ => Err(VerifyError::InvalidSignature(path.to_vec())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsecp256k1::SecretKey;
+
+    fn signed_secp256k1(secret: u8, msg: &[u8]) -> Condition {
+        let secret = SecretKey::parse(&[secret; 32]).unwrap();
+        let mut cond = Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None };
+        cond.sign_secp256k1(&secret, &message_hash(msg)).unwrap();
+        cond
+    }
+
+    #[test]
+    fn test_errors() {
+        let message = decode_fulfillment(&[0xa0], 0).unwrap_err().to_string();
+        let e: Box<dyn std::error::Error> = Box::new(VerifyError::Decode(decode_fulfillment(&[0xa0], 0).unwrap_err()));
+        assert_eq!(e.to_string(), message);
+        assert_eq!(e.source().map(|s| s.to_string()), Some(message));
+        assert!(std::error::Error::source(&VerifyError::Unfulfilled).is_none());
+    }
+
+    #[test]
+    fn test_verify_eval_and_signature_threshold() {
+        let secret = SecretKey::parse(&[2; 32]).unwrap();
+        let cond = |signed: Condition| Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Eval { code: vec![0xe4] },
+                signed,
+                Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None },
+            ]
+        };
+        let is_e4 = |code: &[u8]| code == &[0xe4][..];
+
+        assert_eq!(verify_with_eval(&cond(signed_secp256k1(1, b"msg")), b"msg", &is_e4), Ok(()));
+        assert_eq!(verify_with_eval(&cond(signed_secp256k1(1, b"msg")), b"msg", &no_eval), Err(VerifyError::Unfulfilled));
+        assert_eq!(
+            verify_with_eval(&cond(signed_secp256k1(1, b"other")), b"msg", &is_e4),
+            Err(VerifyError::InvalidSignature(vec![1])));
+    }
+
+    #[test]
+    fn test_verify_with_fetched_condition() {
+        let cond = Threshold { threshold: 1, subconditions: vec![signed_secp256k1(1, b"msg"), Eval { code: vec![1] }] };
+        let fetched = std::cell::Cell::new(0);
+        let fetch = |c: Condition| {
+            fetched.set(fetched.get() + 1);
+            Ok(c)
+        };
+
+        assert_eq!(verify_with_fetched_condition(&cond, b"msg", || fetch(cond.to_anon())), Ok(()));
+        assert_eq!(fetched.get(), 1);
+        assert!(matches!(
+            verify_with_fetched_condition(&cond, b"msg", || fetch(Eval { code: vec![2] })),
+            Err(VerifyError::ConditionMismatch { .. })));
+        assert_eq!(fetched.get(), 2);
+        // no fetch for bad signatures
+        assert_eq!(
+            verify_with_fetched_condition(&cond, b"other", || fetch(cond.to_anon())),
+            Err(VerifyError::InvalidSignature(vec![0])));
+        assert_eq!(fetched.get(), 2);
+    }
+
+    #[test]
+    fn test_ilp_preimage() {
+        // sha256 of 32 zero bytes
+        let condition = hex::decode("66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925").unwrap();
+        assert_eq!(ilp_verify_preimage(&[0; 32], &condition), Ok(()));
+        assert_eq!(ilp_verify_preimage(&[1; 32], &condition), Err(VerifyError::PreimageMismatch(vec![])));
+        assert!(matches!(ilp_verify_preimage(&[0; 31], &condition), Err(VerifyError::Decode(_))));
+
+        // empty preimage example of the crypto-conditions spec
+        let ffil = decode_fulfillment(&hex::decode("A0028000").unwrap(), 0).unwrap();
+        assert_eq!(ffil, Preimage { preimage: vec![] });
+        assert_eq!(
+            hex::encode_upper(ffil.encode_condition()),
+            "A0258020E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855810100");
+        assert_eq!(verify(&ffil, b"any message"), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_height_gated_eval() {
+        let cond = Threshold { threshold: 2, subconditions: vec![signed_secp256k1(1, b"msg"), Eval { code: vec![0xe4] }] };
+        // passes from height 100
+        let timelock = |code: &[u8], ctx: &EvalContext| code == &[0xe4][..] && ctx.height_reached(100);
+        let at = |height| EvalContext { height: Some(height), time: None };
+
+        assert_eq!(verify_with_eval_context(&cond, b"msg", &at(100), &timelock), Ok(()));
+        assert_eq!(verify_with_eval_context(&cond, b"msg", &at(99), &timelock), Err(VerifyError::Unfulfilled));
+        assert_eq!(verify_with_eval_context(&cond, b"msg", &EvalContext::default(), &timelock), Err(VerifyError::Unfulfilled));
+        assert!(!EvalContext::default().time_reached(0));
+    }
+
+    #[test]
+    fn test_verify_prehashed_matches_verify() {
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![
+                signed_secp256k1(1, b"hello"),
+                Eval { code: vec![0xe4] }
+            ]
+        };
+        let mut msg_hash = [0u8; 32];
+
+        msg_hash.copy_from_slice(&sha256(b"hello".to_vec()));
+        assert_eq!(verify(&cond, b"hello"), Ok(()));
+        assert_eq!(verify_prehashed(&cond, &msg_hash), verify(&cond, b"hello"));
+
+        msg_hash.copy_from_slice(&sha256(b"world".to_vec()));
+        assert_eq!(verify(&cond, b"world"), Err(VerifyError::InvalidSignature(vec![0])));
+        assert_eq!(verify_prehashed(&cond, &msg_hash), verify(&cond, b"world"));
+    }
+
+    #[test]
+    fn test_verify_reports_leaf_with_wrong_message() {
+        // stitched from signatures of different messages
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                signed_secp256k1(1, b"hello"),
+                Threshold {
+                    threshold: 1,
+                    subconditions: vec![Eval { code: vec![0xe4] }, signed_secp256k1(2, b"other")]
+                }
+            ]
+        };
+        assert_eq!(verify(&cond, b"hello"), Err(VerifyError::InvalidSignature(vec![1, 1])));
+        assert_eq!(verify(&cond, b"other"), Err(VerifyError::InvalidSignature(vec![0])));
+    }
+
+    #[test]
+    fn test_effective_threshold() {
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![signed_secp256k1(1, b"msg"), Eval { code: vec![0xe4] }]
+        };
+        let always_true = |_: &[u8]| true;
+
+        assert_eq!(effective_threshold(&cond, &always_true), 1);
+        assert_eq!(effective_threshold(&cond, &no_eval), u16::MAX);
+        assert_eq!(verify_with_eval(&cond, b"msg", &always_true), Ok(()));
+        assert_eq!(verify(&cond, b"msg"), Err(VerifyError::Unfulfilled));
+
+        let nested = Threshold {
+            threshold: 2,
+            subconditions: vec![signed_secp256k1(2, b"msg"), Eval { code: vec![1] }, cond]
+        };
+        assert_eq!(effective_threshold(&nested, &always_true), 1);
+        assert_eq!(effective_threshold(&nested, &|code: &[u8]| code == &[1][..]), 1);
+        assert_eq!(effective_threshold(&nested, &no_eval), u16::MAX);
+    }
+
+    fn small_thresholds(count: u8) -> Vec<(Condition, Vec<u8>)> {
+        (0..count)
+            .map(|i| {
+                let msg = vec![i; 32];
+                let cond = Threshold {
+                    threshold: 2,
+                    subconditions: vec![signed_secp256k1(1, &msg), signed_secp256k1(2, &msg), Eval { code: vec![i] }]
+                };
+                (cond, msg)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let mut items = small_thresholds(4);
+        items[2].1 = b"other".to_vec();
+        let outcomes = verify_batch(&items);
+
+        assert_eq!(outcomes.len(), 4);
+        assert_eq!(outcomes[0], Ok(()));
+        assert_eq!(outcomes[2], Err(VerifyError::InvalidSignature(vec![0])));
+        assert_eq!(outcomes[3], Ok(()));
+    }
+
+    #[test]
+    fn test_verify_unfulfilled_threshold() {
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                signed_secp256k1(1, b"hello"),
+                Preimage { preimage: vec![1] }.to_anon()
+            ]
+        };
+        assert_eq!(verify(&cond, b"hello"), Err(VerifyError::Unfulfilled));
+    }
+
+    #[test]
+    fn test_verify_required_signers() {
+        let pubkey = |i| PublicKey::from_secret_key(&SecretKey::parse(&[i; 32]).unwrap());
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                signed_secp256k1(2, b"hello"),
+                signed_secp256k1(3, b"hello")
+            ]
+        };
+
+        assert_eq!(verify(&cond, b"hello"), Ok(()));
+        assert_eq!(verify_with_required_signers(&cond, b"hello", &[pubkey(2)]), Ok(()));
+        assert_eq!(
+            verify_with_required_signers(&cond, b"hello", &[pubkey(3), pubkey(1)]),
+            Err(VerifyError::MissingRequiredSigner(pubkey(1))));
+    }
+
+    #[test]
+    fn test_add_detached_signature() {
+        let secret = SecretKey::parse(&[1; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secret);
+        let other = PublicKey::from_secret_key(&SecretKey::parse(&[2; 32]).unwrap());
+        let unknown = PublicKey::from_secret_key(&SecretKey::parse(&[3; 32]).unwrap());
+        // precomputed by the signing device
+        let signature = libsecp256k1::sign(&message_hash(b"tx"), &secret).0;
+        let mut cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey, signature: None },
+                Secp256k1 { pubkey: other, signature: None },
+                Secp256k1Hash { pubkey_hash: Some(ripemd_sha(&pubkey)), pubkey: None, signature: None }
+            ]
+        };
+
+        assert_eq!(add_signature(&mut cond.clone(), &pubkey, signature, Some(b"other tx")), Err(VerifyError::InvalidSignature(vec![])));
+        assert_eq!(add_signature(&mut cond.clone(), &unknown, signature, None), Err(VerifyError::NoMatchingLeaf(unknown)));
+        assert_eq!(add_signature(&mut cond, &pubkey, signature, Some(b"tx")), Ok(2));
+        assert_eq!(verify(&cond, b"tx"), Ok(()));
+    }
+
+    #[test]
+    fn test_assert_fulfills() {
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![signed_secp256k1(1, b"hello"), Eval { code: vec![0xe4] }]
+        };
+        let other = signed_secp256k1(2, b"hello");
+
+        assert_eq!(assert_fulfills(&cond, &cond), Ok(()));
+        assert_eq!(assert_fulfills(&cond, &cond.to_anon()), Ok(()));
+
+        let e = assert_fulfills(&cond, &other).unwrap_err();
+        assert_eq!(e, VerifyError::ConditionMismatch {
+            fulfillment_fingerprint: cond.fingerprint(),
+            condition_fingerprint: other.fingerprint(),
+        });
+        let message = e.to_string();
+        assert!(message.contains(&hex::encode(cond.fingerprint())));
+        assert!(message.contains(&hex::encode(other.fingerprint())));
+    }
+
+    #[test]
+    fn test_signing_messages() {
+        let pubkey = |i| PublicKey::from_secret_key(&SecretKey::parse(&[i; 32]).unwrap());
+        let cond = Threshold {
+            threshold: 2,
+            subconditions: vec![
+                Secp256k1 { pubkey: pubkey(1), signature: None },
+                Eval { code: vec![0xe4] },
+                Prefix {
+                    prefix: b"cc:".to_vec(),
+                    max_message_len: 32,
+                    subcondition: Box::new(Secp256k1 { pubkey: pubkey(2), signature: None }),
+                },
+            ]
+        };
+
+        assert_eq!(signing_messages(&cond, b"tx"), vec![(vec![0], b"tx".to_vec()), (vec![2, 0], b"cc:tx".to_vec())]);
+
+        // eval designs which sign a different sighash per leaf
+        let per_leaf = signing_messages_with(&cond, b"tx", &|path, msg| [msg, &[path.len() as u8][..]].concat());
+        assert_eq!(per_leaf, vec![(vec![0], b"tx\x01".to_vec()), (vec![2, 0], b"cc:tx\x02".to_vec())]);
+    }
+
+    #[test]
+    fn test_verify_with_external_preimage() {
+        use std::collections::HashMap;
+        let blob = vec![7u8; 1000];
+        let mut cond = Threshold {
+            threshold: 2,
+            subconditions: vec![signed_secp256k1(1, b"msg"), Preimage { preimage: blob.clone() }, Preimage { preimage: vec![1] }]
+        };
+        let fingerprint = cond.fingerprint();
+        let blobs = externalize_preimages(&mut cond, 100);
+        assert_eq!(blobs, vec![blob.clone()]);
+        assert_eq!(cond.fingerprint(), fingerprint);
+
+        let store: HashMap<Vec<u8>, Vec<u8>> = blobs.into_iter().map(|b| (sha256(b.clone()), b)).collect();
+        let mut resolved = 0;
+        let mut resolve = |fingerprint: &[u8]| {
+            resolved += 1;
+            store.get(fingerprint).cloned()
+        };
+        assert_eq!(verify_with_preimages(&cond, b"msg", &mut resolve), Ok(()));
+        assert_eq!(resolved, 1);
+        assert_eq!(verify(&Threshold { threshold: 3, subconditions: children(&cond).to_vec() }, b"msg"), Err(VerifyError::Unfulfilled));
+
+        let mut wrong = |_: &[u8]| Some(vec![8u8; 1000]);
+        assert_eq!(verify_with_preimages(&cond, b"msg", &mut wrong), Err(VerifyError::PreimageMismatch(vec![1])));
+        assert_eq!(verify_with_preimages(&cond, b"msg", &mut |_: &[u8]| None), Ok(()));
+    }
+
+    #[test]
+    fn test_check_subtypes() {
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![signed_secp256k1(1, b"hello"), Preimage { preimage: vec![1] }]
+        };
+        let with_subtypes = |ids: Vec<u8>| match cond.to_anon() {
+            Anon { cond_type, fingerprint, cost, .. } => Anon { cond_type, fingerprint, cost, subtypes: ids.into_iter().collect() },
+            _ => panic!("not anon"),
+        };
+
+        assert_eq!(check_subtypes(&cond, &cond.to_anon()), Ok(()));
+        // eval over-claimed, with the same fingerprint
+        let overclaimed = with_subtypes(vec![PreimageType.id(), Secp256k1Type.id(), EvalType.id()]);
+        assert_eq!(overclaimed.fingerprint(), cond.fingerprint());
+        assert_eq!(check_subtypes(&cond, &overclaimed), Err(VerifyError::OverclaimedSubtype(EvalType)));
+        assert_eq!(
+            check_subtypes(&cond, &with_subtypes(vec![Secp256k1Type.id()])),
+            Err(VerifyError::UnderclaimedSubtype(PreimageType)));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_verify_against_json() {
+        let pubkey = |i| PublicKey::from_secret_key(&SecretKey::parse(&[i; 32]).unwrap());
+        let cond = Threshold {
+            threshold: 1,
+            subconditions: vec![signed_secp256k1(1, b"hello"), Eval { code: vec![0xe4] }]
+        };
+        let ffil_hex = hex::encode(cond.encode_fulfillment(0).unwrap());
+        let cond_json = serde_json::json!({
+            "type": "threshold-sha-256",
+            "threshold": 1,
+            "subfulfillments": [
+                { "type": "secp256k1-sha-256", "publicKey": hex::encode(&pubkey(1).serialize_compressed()[..]) },
+                { "type": "eval-sha-256", "codehex": "e4" }
+            ]
+        });
+        let other_json = serde_json::json!({ "type": "secp256k1-sha-256", "publicKey": hex::encode(&pubkey(2).serialize_compressed()[..]) });
+
+        assert_eq!(verify_against_json(&ffil_hex, &cond_json, b"hello"), Ok(()));
+        assert_eq!(verify_against_json(&ffil_hex, &to_json(&cond.to_anon()), b"hello"), Ok(()));
+        assert_eq!(verify_against_json(&ffil_hex, &cond_json, b"world"), Err(VerifyError::InvalidSignature(vec![0])));
+        assert!(matches!(
+            verify_against_json(&ffil_hex, &other_json, b"hello"),
+            Err(VerifyError::ConditionMismatch { .. })));
+        assert!(verify_against_json("zz", &cond_json, b"hello").is_err());
+    }
+}
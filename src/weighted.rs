@@ -0,0 +1,83 @@
+use crate::*;
+
+// Weighted thresholds are not part of the crypto-conditions spec and won't interop with the
+// reference (or the komodo C) implementation: there is no DER encoding, fingerprint or cost for them.
+// It is a wrapper around standard conditions, which still encode as usual, for deployments which
+// check a sum of weights in their own code instead of a count of subconditions.
+
+/// threshold met when the weights of the satisfied subconditions sum up to at least threshold
+#[derive(Clone, PartialEq, Debug)]
+pub struct WeightedThreshold {
+    pub threshold: u64,
+    /// (weight, subcondition) pairs
+    pub subconditions: Vec<(u64, Condition)>,
+}
+
+impl WeightedThreshold {
+    /// sum of the weights of all subconditions
+    pub fn total_weight(&self) -> u64 {
+        self.subconditions.iter().fold(0u64, |sum, (weight, _)| sum.saturating_add(*weight))
+    }
+
+    /// verify each subcondition like verify() and check the weights of the fulfilled ones against the threshold.
+    /// Invalid signatures are errors, with the index of the subcondition in front of the path, unfulfilled
+    /// subconditions just don't add their weight
+    pub fn verify(&self, msg: &[u8]) -> Result<(), VerifyError> {
+        let mut weight = 0u64;
+        for (i, (w, cond)) in self.subconditions.iter().enumerate() {
+            match verify(cond, msg) {
+                Ok(()) => weight = weight.saturating_add(*w),
+                Err(VerifyError::Unfulfilled) => {},
+                Err(VerifyError::InvalidSignature(path)) => {
+                    return Err(VerifyError::InvalidSignature([vec![i], path].concat()));
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        match weight >= self.threshold {
+            true => Ok(()),
+            _ => Err(VerifyError::Unfulfilled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsecp256k1::{PublicKey, SecretKey};
+
+    fn secp256k1(secret: u8, signed: bool) -> Condition {
+        let secret = SecretKey::parse(&[secret; 32]).unwrap();
+        let mut cond = Secp256k1 { pubkey: PublicKey::from_secret_key(&secret), signature: None };
+        if signed {
+            cond.sign_secp256k1(&secret, &crate::verify::message_hash(b"msg")).unwrap();
+        }
+        cond
+    }
+
+    #[test]
+    fn test_weighted_threshold_verify() {
+        let weighted = |threshold, signed: [bool; 3]| WeightedThreshold {
+            threshold,
+            subconditions: vec![
+                (3, secp256k1(1, signed[0])),
+                (1, secp256k1(2, signed[1])),
+                (1, secp256k1(3, signed[2])),
+            ]
+        };
+
+        assert_eq!(weighted(3, [true, false, false]).total_weight(), 5);
+        assert_eq!(weighted(3, [true, false, false]).verify(b"msg"), Ok(()));
+        assert_eq!(weighted(3, [false, true, true]).verify(b"msg"), Err(VerifyError::Unfulfilled));
+        assert_eq!(weighted(2, [false, true, true]).verify(b"msg"), Ok(()));
+        assert_eq!(weighted(5, [true, true, true]).verify(b"msg"), Ok(()));
+        assert_eq!(weighted(1, [true, false, false]).verify(b"other"), Err(VerifyError::InvalidSignature(vec![0])));
+        assert_eq!(weighted(1, [false, false, true]).verify(b"other"), Err(VerifyError::InvalidSignature(vec![2])));
+        let nested = WeightedThreshold {
+            threshold: 1,
+            subconditions: vec![(1, secp256k1(1, false)), (1, Threshold { threshold: 1, subconditions: vec![secp256k1(2, true)] })]
+        };
+        assert_eq!(nested.verify(b"other"), Err(VerifyError::InvalidSignature(vec![1, 0])));
+        assert_eq!(weighted(0, [false, false, false]).verify(b"msg"), Ok(()));
+    }
+}
@@ -0,0 +1,45 @@
+use libsecp256k1::SecretKey;
+
+use crate::*;
+
+// WIF is base58check of a version byte, the 32 byte secret and an optional 0x01 compressed pubkey flag.
+// The version byte differs between chains (0x80 for bitcoin, 0xbc for komodo) so any is accepted
+
+/// parse a secret key from WIF
+pub fn secret_from_wif(wif: &str) -> Result<SecretKey, ConditionDecodeError> {
+    let data = bs58::decode(wif)
+        .with_check(None)
+        .into_vec()
+        .map_err(|e| ConditionDecodeError::Malformed(e.to_string()))?;
+    let secret = match data.as_slice() {
+        [_version, secret @ ..] if secret.len() == 32 => secret,
+        [_version, secret @ .., 0x01] if secret.len() == 32 => secret,
+        _ => return Err(ConditionDecodeError::Malformed("Invalid WIF length".into())),
+    };
+    SecretKey::parse_slice(secret).map_err(|e| ConditionDecodeError::Malformed(e.to_string()))
+}
+
+/// unsigned secp256k1 condition of the pubkey of a WIF secret key
+pub fn secp256k1_condition_from_wif(wif: &str) -> Result<Condition, ConditionDecodeError> {
+    Ok(secp256k1_condition_from_secret(&secret_from_wif(wif)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secp256k1_condition_from_wif() {
+        let expected = secp256k1_condition_from_secret(&SecretKey::parse(&[1; 32]).unwrap());
+        // compressed and uncompressed bitcoin and compressed komodo WIFs of the secret 0x0101..01
+        for wif in &[
+            "KwFfNUhSDaASSAwtG7ssQM1uVX8RgX5GHWnnLfhfiQDigjioWXHH",
+            "5HpjE2Hs7vjU4SN3YyPQCdhzCu92WoEeuE6PWNuiPyTu3ESGnzn",
+            "Up3VgAKQio8guDjySfZTAnh8RbBZmdLt42AbuvVMB7SRabip7y9r",
+        ] {
+            assert_eq!(secp256k1_condition_from_wif(wif), Ok(expected.clone()));
+        }
+        // bad checksum
+        assert!(secp256k1_condition_from_wif("KwFfNUhSDaASSAwtG7ssQM1uVX8RgX5GHWnnLfhfiQDigjioWXHJ").is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use bip32::{DerivationPath, XPub};
+use libsecp256k1::PublicKey;
+use std::str::FromStr;
+
+use crate::*;
+
+fn bip32_err(e: bip32::Error) -> ConditionDecodeError {
+    ConditionDecodeError::Malformed(e.to_string())
+}
+
+/// unsigned secp256k1 condition of the child key of xpub at path (like "m/0/5"),
+/// only non hardened derivation is possible from an xpub
+pub fn condition_from_xpub(xpub: &str, path: &str) -> Result<Condition, ConditionDecodeError> {
+    let mut child = XPub::from_str(xpub).map_err(bip32_err)?;
+    for child_number in DerivationPath::from_str(path).map_err(bip32_err)?.iter() {
+        child = child.derive_child(child_number).map_err(bip32_err)?;
+    }
+    let pubkey = PublicKey::parse_slice(&child.to_bytes(), None)
+        .map_err(|e| ConditionDecodeError::Malformed(e.to_string()))?;
+    Ok(Secp256k1 { pubkey, signature: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // master key of bip32 test vector 2
+    const XPUB: &str = "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB";
+
+    fn secp256k1(pubkey_hex: &str) -> Condition {
+        let pubkey = PublicKey::parse_slice(&hex::decode(pubkey_hex).unwrap(), None).unwrap();
+        Secp256k1 { pubkey, signature: None }
+    }
+
+    #[test]
+    fn test_condition_from_xpub() {
+        let m_0 = secp256k1("02fc9e5af0ac8d9b3cecfe2a888e2117ba3d089d8585886c9c826b6b22a98d12ea");
+        let m_0_5 = secp256k1("03ecd17b9d0cfe18ae10c82d4883229464d1b9f9d55e44db92218df5aaec69b93b");
+
+        assert_eq!(condition_from_xpub(XPUB, "m/0").unwrap().fingerprint(), m_0.fingerprint());
+        assert_eq!(condition_from_xpub(XPUB, "m/0/5"), Ok(m_0_5));
+        assert!(condition_from_xpub(XPUB, "m/0'").is_err());
+        assert!(condition_from_xpub("xpub", "m/0").is_err());
+    }
+}